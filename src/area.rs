@@ -0,0 +1,73 @@
+use image::GrayImage;
+
+use crate::sinusoid::gamma_table;
+
+/// Averages of arbitrary rectangles of an image.
+///
+/// The image is kept as a summed area table, so the average of any rectangle
+/// can be looked up in constant time. This suits methods that sample the image
+/// along rotated lines or grids, where averaging whole bands of rows up front
+/// (as `average_rows` does) doesn't work.
+pub(crate) struct AreaAverages {
+    /// Image width and height in pixels
+    width: usize,
+    height: usize,
+
+    /// Sum of the pixels above and to the left of each corner, with
+    /// `width + 1` corners per row
+    sums: Vec<u64>,
+
+    /// Gamma correction lookup table
+    gamma: Vec<u8>,
+}
+
+impl AreaAverages {
+    /// Build the table for an image.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `gamma` - Gamma correction applied to the averages.
+    pub(crate) fn new(luma: &GrayImage, gamma: f32) -> Self {
+        let (width, height) = (luma.width() as usize, luma.height() as usize);
+
+        let mut sums = vec![0u64; (width + 1) * (height + 1)];
+        for y in 0..height {
+            let mut row_sum = 0;
+            for x in 0..width {
+                row_sum += u64::from(luma.as_raw()[y * width + x]);
+                sums[(y + 1) * (width + 1) + x + 1] = sums[y * (width + 1) + x + 1] + row_sum;
+            }
+        }
+
+        AreaAverages {
+            width,
+            height,
+            sums,
+            gamma: gamma_table(gamma),
+        }
+    }
+
+    /// Image width and height in pixels.
+    pub(crate) fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Gamma corrected average of the pixels in a rectangle, given in image
+    /// pixels. The rectangle is clamped to the image, but always covers at
+    /// least one pixel.
+    pub(crate) fn average(&self, x0: f32, y0: f32, x1: f32, y1: f32) -> u8 {
+        let span = |a: f32, b: f32, size: usize| {
+            let start = (a.floor().max(0.) as usize).min(size - 1);
+            let end = (b.ceil().max(0.) as usize).clamp(start + 1, size);
+            (start, end)
+        };
+        let (x0, x1) = span(x0, x1, self.width);
+        let (y0, y1) = span(y0, y1, self.height);
+
+        let corner = |x: usize, y: usize| self.sums[y * (self.width + 1) + x];
+        let sum = corner(x1, y1) + corner(x0, y0) - corner(x0, y1) - corner(x1, y0);
+        let count = ((x1 - x0) * (y1 - y0)) as u64;
+
+        self.gamma[(sum / count) as usize]
+    }
+}
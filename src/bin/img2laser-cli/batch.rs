@@ -0,0 +1,414 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+
+use image::DynamicImage;
+
+use img2laser::{
+    CancellationToken, Geometry, ImageProcessError, MachineProfile, OutputFormat, Separation,
+    SinusoidShadingConfig,
+};
+
+/// File extensions that are picked up when an input is a directory.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "tif", "tiff", "bmp", "webp"];
+
+/// The path used on the command line for stdin (as input) or stdout (as output).
+pub const STDIO: &str = "-";
+
+/// Whether `path` refers to stdin/stdout rather than a file.
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+/// A single unit of work: one input image and where its SVG should go.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+/// Expand the inputs given on the command line into a list of image paths.
+///
+/// Each input may be a file, a directory (every image directly inside it is
+/// used), a glob pattern or `-` for stdin. Inputs that can't be expanded are
+/// returned as errors so they can be reported alongside the per-file failures.
+///
+/// # Arguments
+/// * `inputs` - The raw inputs from the command line.
+///
+/// # Returns
+/// * The expanded image paths and a list of (input, error message) pairs.
+pub fn expand_inputs(inputs: &[String]) -> (Vec<PathBuf>, Vec<(String, String)>) {
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+
+        if is_stdio(path) {
+            // Stdin can only be read once
+            if paths.iter().any(|p: &PathBuf| is_stdio(p)) {
+                errors.push((input.clone(), "stdin given more than once".to_string()));
+            } else {
+                paths.push(path.to_path_buf());
+            }
+        } else if path.is_dir() {
+            match read_image_dir(path) {
+                Ok(mut found) => paths.append(&mut found),
+                Err(e) => errors.push((input.clone(), e.to_string())),
+            }
+        } else if path.exists() {
+            paths.push(path.to_path_buf());
+        } else {
+            match glob::glob(input) {
+                Ok(matches) => {
+                    let mut found: Vec<PathBuf> = matches
+                        .filter_map(Result::ok)
+                        .filter(|p| p.is_file())
+                        .collect();
+
+                    if found.is_empty() {
+                        errors.push((input.clone(), "no matching files".to_string()));
+                    }
+                    paths.append(&mut found);
+                }
+                Err(e) => errors.push((input.clone(), e.to_string())),
+            }
+        }
+    }
+
+    (paths, errors)
+}
+
+/// List the images directly inside `dir`, sorted by name.
+fn read_image_dir(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_image = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+            .unwrap_or(false);
+
+        if path.is_file() && is_image {
+            found.push(path);
+        }
+    }
+
+    found.sort();
+    Ok(found)
+}
+
+/// Check that every `{key}` in the file name template can be filled in.
+///
+/// `{stem}` is the input file name without its extension (`stdin` when
+/// reading from stdin). Every field in `SinusoidShadingConfig::FIELDS` is also
+/// available, e.g. `{lines}`.
+pub fn validate_template(template: &str) -> Result<(), String> {
+    for key in template_keys(template)? {
+        if key != "stem" && !SinusoidShadingConfig::FIELDS.contains(&key) {
+            return Err(format!("unknown key `{{{}}}` in name template", key));
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill in a file name template for a given input. See `validate_template`.
+pub fn render_template(template: &str, input: &Path, config: &SinusoidShadingConfig) -> String {
+    let stem = if is_stdio(input) {
+        "stdin".to_string()
+    } else {
+        input
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    let mut name = template.replace("{stem}", &stem);
+    for field in SinusoidShadingConfig::FIELDS {
+        name = name.replace(&format!("{{{}}}", field), &config.get_field(field));
+    }
+
+    name
+}
+
+/// Output path next to `input`, named by `template`. An image read from stdin
+/// is written to stdout.
+pub fn default_output(input: &Path, template: &str, config: &SinusoidShadingConfig) -> PathBuf {
+    if is_stdio(input) {
+        PathBuf::from(STDIO)
+    } else {
+        input.with_file_name(render_template(template, input, config))
+    }
+}
+
+/// Pull the `{key}` names out of a template.
+fn template_keys(template: &str) -> Result<Vec<&str>, String> {
+    let mut keys = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "unclosed `{` in name template".to_string())?;
+        keys.push(&rest[start + 1..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(keys)
+}
+
+/// Pair every input with its output path.
+///
+/// Outputs go to `out_dir` when given, otherwise next to their input (or to
+/// stdout for an image read from stdin). Two inputs mapping to the same output
+/// would silently overwrite each other, so that is reported as an error
+/// instead.
+pub fn plan_jobs(
+    inputs: Vec<PathBuf>,
+    out_dir: Option<&Path>,
+    template: &str,
+    config: &SinusoidShadingConfig,
+) -> (Vec<Job>, Vec<(String, String)>) {
+    let mut jobs = Vec::new();
+    let mut errors = Vec::new();
+    let mut seen = HashSet::new();
+
+    for input in inputs {
+        let output = match out_dir {
+            Some(dir) => dir.join(render_template(template, &input, config)),
+            None => default_output(&input, template, config),
+        };
+
+        if seen.insert(output.clone()) {
+            jobs.push(Job { input, output });
+        } else {
+            errors.push((
+                input.display().to_string(),
+                format!(
+                    "output {} is already used by another input",
+                    output.display()
+                ),
+            ));
+        }
+    }
+
+    (jobs, errors)
+}
+
+/// Load an image from a file, or from stdin when `path` is `-`.
+///
+/// The format of an image read from stdin is guessed from its contents.
+pub fn load_image(path: &Path) -> Result<DynamicImage, ImageProcessError> {
+    if is_stdio(path) {
+        let mut bytes = Vec::new();
+        io::stdin().lock().read_to_end(&mut bytes)?;
+        Ok(image::load_from_memory(&bytes)?)
+    } else {
+        Ok(image::open(path)?)
+    }
+}
+
+/// Read a preset file and apply it to `config`. If there is no such file,
+/// `path` may also name a built-in preset, e.g. `hairline`.
+///
+/// See `SinusoidShadingConfig::apply_preset` for the format.
+pub fn load_preset(
+    path: &Path,
+    config: &mut SinusoidShadingConfig,
+) -> Result<(), ImageProcessError> {
+    let preset = match fs::read_to_string(path) {
+        Ok(preset) => preset,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            match path.to_str().and_then(img2laser::builtin_preset) {
+                Some(preset) => preset.to_string(),
+                None => return Err(e.into()),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    config.apply_preset(&preset)
+}
+
+/// Read a machine profile file, or find a built-in profile by name.
+///
+/// See `MachineProfile::parse` for the format.
+pub fn load_machine(name: &str) -> Result<MachineProfile, ImageProcessError> {
+    match fs::read_to_string(name) {
+        Ok(profile) => MachineProfile::parse(&profile),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            MachineProfile::builtin(name).ok_or_else(|| e.into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether an option was given on the command line, rather than left at its
+/// default.
+///
+/// # Arguments
+/// * `matches` - The matches of the subcommand.
+/// * `field` - The option, by its field name, e.g. `stroke_width`.
+pub fn given(matches: &ArgMatches, field: &str) -> bool {
+    matches.value_source(field) == Some(ValueSource::CommandLine)
+}
+
+/// Create a writer for a file, or for stdout when `path` is `-`, and pass it
+/// to `write`.
+pub fn write_output<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    if is_stdio(path) {
+        let mut stdout = io::stdout().lock();
+        write(&mut stdout)?;
+        stdout.flush()
+    } else {
+        let mut file = BufWriter::new(File::create(path)?);
+        write(&mut file)?;
+        file.flush()
+    }
+}
+
+/// One rendered output file
+pub struct Output {
+    /// The pen the file is for, when there is one file per pen
+    pub pen: Option<String>,
+
+    /// Contents of the file
+    pub data: Vec<u8>,
+}
+
+impl Output {
+    /// Where to write the file, given the job's output path.
+    pub fn path(&self, output: &Path) -> PathBuf {
+        match &self.pen {
+            Some(pen) => pen_output(output, pen),
+            None => output.to_path_buf(),
+        }
+    }
+}
+
+/// Path of the file for one pen of a colour separation, e.g. `face-cyan.gcode`
+/// for `face.gcode`.
+fn pen_output(path: &Path, pen: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-{}", pen));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Process an image into the machine's output format.
+///
+/// # Arguments
+/// * `img` - The input image.
+/// * `config` - Shading options.
+/// * `machine` - The machine, which decides the format.
+/// * `progress` - Called with the rows done and the total, see
+///   `img2laser::process_image_with_progress`.
+///
+/// # Returns
+/// * The contents of the output file, see `write_outputs`.
+pub fn render(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    progress: impl Fn(usize, usize) + Sync,
+) -> Result<Vec<Output>, ImageProcessError> {
+    let geometry =
+        img2laser::shade_with_progress(img, config, progress, &CancellationToken::new())?;
+    write_outputs(&geometry, config, machine)
+}
+
+/// Write shaded geometry in the machine's output format.
+///
+/// # Arguments
+/// * `geometry` - The geometry, shaded with `config`.
+/// * `config` - Shading options.
+/// * `machine` - The machine, which decides the format.
+///
+/// # Returns
+/// * The contents of the output file. G-code for a colour separation is
+///   split into one file per pen instead, each with the pen's name.
+pub fn write_outputs(
+    geometry: &Geometry,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+) -> Result<Vec<Output>, ImageProcessError> {
+    match machine.format {
+        OutputFormat::Svg => {
+            let svg_img = img2laser::geometry_document(geometry, config);
+            let mut svg_data = Vec::new();
+            svg::write(&mut svg_data, &svg_img)?;
+            Ok(vec![Output {
+                pen: None,
+                data: svg_data,
+            }])
+        }
+        OutputFormat::Gcode if config.separation != Separation::None => {
+            let pens = img2laser::geometry_gcode_layers(geometry, config, machine);
+            Ok(pens
+                .into_iter()
+                .map(|(pen, gcode)| Output {
+                    pen: Some(pen),
+                    data: gcode.into_bytes(),
+                })
+                .collect())
+        }
+        OutputFormat::Gcode => {
+            let gcode = img2laser::geometry_gcode(geometry, config, machine);
+            Ok(vec![Output {
+                pen: None,
+                data: gcode.into_bytes(),
+            }])
+        }
+    }
+}
+
+/// Load, process and save a single image.
+///
+/// # Arguments
+/// * `job` - The input image and output file.
+/// * `config` - Shading options.
+/// * `machine` - The machine, which decides the format.
+/// * `progress` - Called with the rows done and the total.
+///
+/// # Returns
+/// * The files written, which are `job.output` or one file per pen (see
+///   `render`).
+pub fn run_job(
+    job: &Job,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    progress: impl Fn(usize, usize) + Sync,
+) -> Result<Vec<PathBuf>, ImageProcessError> {
+    let img = load_image(&job.input)?;
+    let outputs = render(&img, config, machine, progress)?;
+
+    if outputs.len() > 1 && is_stdio(&job.output) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "one file per pen can't be written to stdout",
+        )
+        .into());
+    }
+
+    let mut written = Vec::new();
+    for output in outputs {
+        let path = output.path(&job.output);
+        write_output(&path, |w| w.write_all(&output.data))?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
@@ -0,0 +1,151 @@
+use std::path::{Path, PathBuf};
+
+use clap::{ArgMatches, Args};
+
+use image::{DynamicImage, GrayImage, Luma};
+
+use img2laser::{
+    GcodeBackend, ImageProcessError, MachineProfile, OutputBackend, OutputFormat,
+    SinusoidShadingConfig, SvgBackend, TestCard,
+};
+
+use crate::batch;
+
+#[derive(Args, Debug)]
+/// Options for the `calibrate` subcommand
+pub struct CalibrateArgs {
+    /// Output path, or `-` for stdout. Defaults to `calibration.svg`, or
+    /// `calibration.gcode` for G-code.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Number of grey levels, from black to white
+    #[arg(long, default_value_t = 10)]
+    steps: u32,
+
+    /// Write a material test card instead: a labelled grid of swatches of
+    /// one grey level, shaded with every combination of `--max-freqs`,
+    /// `--amplitudes` and `--line-counts`
+    #[arg(long)]
+    card: bool,
+
+    /// Grey level of the test card swatches, from 0 (black) to 255 (white)
+    #[arg(long, default_value_t = 0, requires = "card")]
+    grey: u8,
+
+    /// Maximum frequencies to try on the test card, one per column, e.g.
+    /// `1,2,3`. Defaults to `--max-freq`.
+    #[arg(long, value_delimiter = ',', requires = "card")]
+    max_freqs: Vec<f32>,
+
+    /// Amplitudes to try on the test card, e.g. `0.3,0.5`. Defaults to
+    /// `--amplitude`.
+    #[arg(long, value_delimiter = ',', requires = "card")]
+    amplitudes: Vec<f32>,
+
+    /// Line counts to try on the test card, e.g. `64,96`. Each amplitude
+    /// and line count makes a row. Defaults to `--lines`.
+    #[arg(long, value_delimiter = ',', requires = "card")]
+    line_counts: Vec<usize>,
+
+    /// Machine profile file, or the name of a built-in profile, as for
+    /// `render --machine`
+    #[arg(long)]
+    machine: Option<String>,
+
+    /// Output format. Defaults to the machine's preferred format, or SVG.
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    #[command(flatten)]
+    config: SinusoidShadingConfig,
+}
+
+/// Write a test pattern of grey levels, or a test card of settings, shaded
+/// with the given settings.
+///
+/// Burning the pattern on a new material shows how each tone comes out.
+///
+/// # Arguments
+/// * `args` - The options.
+/// * `matches` - The matches the options were read from, to tell which
+///   options were given.
+pub fn run(args: CalibrateArgs, matches: &ArgMatches) -> Result<(), ImageProcessError> {
+    let mut machine = match &args.machine {
+        Some(name) => batch::load_machine(name)?,
+        None => MachineProfile::default(),
+    };
+    if let Some(format) = args.format {
+        machine.format = format;
+    }
+
+    let mut config = args.config.clone();
+    machine.apply(&mut config, |field| batch::given(matches, field));
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| format!("calibration.{}", machine.format.extension()).into());
+
+    if args.card {
+        return write_card(&args, &output, &config, machine);
+    }
+
+    let img = grey_steps(&config, args.steps.max(2));
+    let outputs = batch::render(&img, &config, &machine, |_, _| {})?;
+    for rendered in outputs {
+        let path = rendered.path(&output);
+        batch::write_output(&path, |w| w.write_all(&rendered.data))?;
+    }
+
+    Ok(())
+}
+
+/// Write a material test card, see `img2laser::test_card`.
+fn write_card(
+    args: &CalibrateArgs,
+    output: &Path,
+    config: &SinusoidShadingConfig,
+    machine: MachineProfile,
+) -> Result<(), ImageProcessError> {
+    // Options left out are taken from the shading options
+    let mut card = TestCard::new(config);
+    card.grey = args.grey;
+    if !args.max_freqs.is_empty() {
+        card.max_freqs = args.max_freqs.clone();
+    }
+    if !args.amplitudes.is_empty() {
+        card.amplitudes = args.amplitudes.clone();
+    }
+    if !args.line_counts.is_empty() {
+        card.lines = args.line_counts.clone();
+    }
+
+    let geometry = img2laser::test_card(&card, config);
+    match machine.format {
+        OutputFormat::Svg => {
+            let document = SvgBackend.write(&geometry, config);
+            batch::write_output(output, |w| svg::write(w, &document))?;
+        }
+        OutputFormat::Gcode => {
+            let gcode = GcodeBackend::new(machine).write(&geometry, config);
+            batch::write_output(output, |w| w.write_all(gcode.as_bytes()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Create an image of vertical bands stepping from black (left) to white
+/// (right), the same size as the output.
+fn grey_steps(config: &SinusoidShadingConfig, steps: u32) -> DynamicImage {
+    let width = config.width.max(1) as u32;
+    let height = config.height.max(1) as u32;
+
+    let img = GrayImage::from_fn(width, height, |x, _| {
+        let step = (x * steps / width).min(steps - 1);
+        Luma([(step * 255 / (steps - 1)) as u8])
+    });
+
+    DynamicImage::ImageLuma8(img)
+}
@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use clap::Args;
+
+use img2laser::{ImageProcessError, SinusoidShadingConfig};
+
+use crate::batch;
+
+/// Number of bars in the printed histogram
+const HISTOGRAM_BINS: usize = 16;
+
+/// Width of the longest histogram bar, in characters
+const HISTOGRAM_WIDTH: usize = 50;
+
+#[derive(Args, Debug)]
+/// Options for the `info` subcommand
+pub struct InfoArgs {
+    /// Input image path, or `-` to read from stdin
+    input: PathBuf,
+
+    #[command(flatten)]
+    config: SinusoidShadingConfig,
+}
+
+/// Print statistics about the input image.
+pub fn run(args: InfoArgs) -> Result<(), ImageProcessError> {
+    let img = batch::load_image(&args.input)?;
+    let stats = img2laser::image_stats(&img, &args.config);
+
+    println!("Image:      {} x {} px", stats.width, stats.height);
+    println!(
+        "Lines:      {} ({} points each)",
+        stats.lines, stats.points_per_line
+    );
+    println!(
+        "Row means:  {} (darkest) to {} (lightest)",
+        stats.darkest, stats.lightest
+    );
+    println!(
+        "Frequency:  {} to {} (sampled at {})",
+        stats.min_freq, stats.max_freq, args.config.sample_freq
+    );
+
+    // Frequencies above half the sample frequency can't be represented
+    if stats.max_freq > args.config.sample_freq / 2. {
+        println!("warning: max frequency is above half the sample frequency");
+    }
+
+    // Group the 256 grey levels into fewer, wider bins
+    let bin_size = 256 / HISTOGRAM_BINS;
+    let bins: Vec<u32> = stats
+        .histogram
+        .chunks(bin_size)
+        .map(|c| c.iter().sum())
+        .collect();
+    let peak = bins.iter().copied().max().unwrap_or(0).max(1);
+
+    println!("Histogram:");
+    for (i, count) in bins.iter().enumerate() {
+        let bar = (*count as usize * HISTOGRAM_WIDTH) / peak as usize;
+        println!(
+            "  {:3}-{:3} {:>8} {}",
+            i * bin_size,
+            (i + 1) * bin_size - 1,
+            count,
+            "#".repeat(bar)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+mod batch;
+mod calibrate;
+mod info;
+mod preview;
+mod progress;
+mod raster;
+mod render;
+mod sweep;
+mod watch;
+
+use std::process::ExitCode;
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+/// Convert bitmap images into laser-ready SVGs using sine wave line shading
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert images into sine wave line shaded SVGs
+    Render(render::RenderArgs),
+
+    /// Write a raster PNG preview of the line shaded image
+    Preview(preview::PreviewArgs),
+
+    /// Dither an image to a 1-bit PNG or BMP for the laser's raster mode
+    Raster(raster::RasterArgs),
+
+    /// Print image dimensions, histogram and the derived frequency range
+    Info(info::InfoArgs),
+
+    /// Write a material test pattern of grey levels
+    Calibrate(calibrate::CalibrateArgs),
+
+    /// Render a grid of the image with one or two options varied
+    Sweep(sweep::SweepArgs),
+}
+
+fn main() -> ExitCode {
+    // The matches tell options given on the command line from defaults
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let (_, args_matches) = matches.subcommand().expect("a subcommand is required");
+
+    let result = match cli.command {
+        Command::Render(args) => return render::run(args, args_matches),
+        Command::Preview(args) => preview::run(args),
+        Command::Raster(args) => raster::run(args),
+        Command::Info(args) => info::run(args),
+        Command::Calibrate(args) => calibrate::run(args, args_matches),
+        Command::Sweep(args) => sweep::run(args),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use clap::Args;
+
+use image::ImageOutputFormat;
+
+use img2laser::{ImageProcessError, SinusoidShadingConfig};
+
+use crate::batch;
+
+#[derive(Args, Debug)]
+/// Options for the `preview` subcommand
+pub struct PreviewArgs {
+    /// Input image path, or `-` to read from stdin
+    input: PathBuf,
+
+    /// Output PNG path, or `-` for stdout. Defaults to `{stem}_preview.png`
+    /// next to the input.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output pixels per SVG unit
+    #[arg(long, default_value_t = 1.)]
+    scale: f32,
+
+    #[command(flatten)]
+    config: SinusoidShadingConfig,
+}
+
+/// Render the input image as a raster PNG.
+pub fn run(args: PreviewArgs) -> Result<(), ImageProcessError> {
+    let out_path = match args.output {
+        Some(path) => path,
+        None => batch::default_output(&args.input, "{stem}_preview.png", &args.config),
+    };
+
+    let img = batch::load_image(&args.input)?;
+    let preview = img2laser::render_preview(&img, &args.config, args.scale);
+
+    // PNG encoding needs a seekable writer, so encode in memory first
+    let mut png = Vec::new();
+    preview.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    batch::write_output(&out_path, |w| w.write_all(&png))?;
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+
+/// Width of the bar in characters, not counting the text after it
+const BAR_WIDTH: usize = 30;
+
+/// A progress bar on stderr for a batch of images.
+///
+/// Each image reports its own progress, and the bar shows the average over
+/// the whole batch. Nothing is drawn unless stderr is a terminal, so logs and
+/// pipes don't fill up with bar updates.
+pub struct ProgressBar {
+    enabled: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Fraction done (0 to 1) of each image
+    fractions: Vec<f32>,
+
+    /// Number of filled bar characters last drawn, to skip redundant redraws
+    drawn: Option<usize>,
+}
+
+impl ProgressBar {
+    /// Create a bar for `jobs` images.
+    pub fn new(jobs: usize) -> Self {
+        ProgressBar {
+            enabled: io::stderr().is_terminal(),
+            state: Mutex::new(State {
+                fractions: vec![0.; jobs],
+                drawn: None,
+            }),
+        }
+    }
+
+    /// Record the progress of one image.
+    ///
+    /// # Arguments
+    /// * `job` - Index of the image in the batch.
+    /// * `done` - Rows done so far.
+    /// * `total` - Rows in the image.
+    pub fn update(&self, job: usize, done: usize, total: usize) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        // Rows finish on several threads, so reports may arrive out of order
+        let fraction = done as f32 / total.max(1) as f32;
+        state.fractions[job] = state.fractions[job].max(fraction);
+
+        let filled = (state.overall() * BAR_WIDTH as f32) as usize;
+        if state.drawn != Some(filled) {
+            state.drawn = Some(filled);
+            state.draw(filled);
+        }
+    }
+
+    /// Print a line of text above the bar.
+    pub fn println(&self, message: &str) {
+        let state = self.state.lock().unwrap();
+        if self.enabled {
+            eprint!("\r\x1b[K");
+        }
+        eprintln!("{}", message);
+        if let (true, Some(filled)) = (self.enabled, state.drawn) {
+            state.draw(filled);
+        }
+    }
+
+    /// Remove the bar.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if self.enabled && state.drawn.is_some() {
+            eprint!("\r\x1b[K");
+            let _ = io::stderr().flush();
+        }
+        state.drawn = None;
+    }
+}
+
+impl State {
+    /// Average fraction done over the batch.
+    fn overall(&self) -> f32 {
+        self.fractions.iter().sum::<f32>() / self.fractions.len().max(1) as f32
+    }
+
+    fn draw(&self, filled: usize) {
+        let images_done = self.fractions.iter().filter(|&&f| f >= 1.).count();
+        eprint!(
+            "\r[{}{}] {:3.0}% ({}/{} images)",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            self.overall() * 100.,
+            images_done,
+            self.fractions.len()
+        );
+        let _ = io::stderr().flush();
+    }
+}
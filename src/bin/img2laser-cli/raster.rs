@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+
+use clap::Args;
+
+use img2laser::{Dither, ImageProcessError, RasterFormat, SinusoidShadingConfig};
+
+use crate::batch;
+
+#[derive(Args, Debug)]
+/// Options for the `raster` subcommand
+pub struct RasterArgs {
+    /// Input image path, or `-` to read from stdin
+    input: PathBuf,
+
+    /// Output path, or `-` for stdout. Defaults to `{stem}_raster.png` (or
+    /// `.bmp`) next to the input.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Resolution of the laser's raster mode, in dots per inch
+    #[arg(long, default_value_t = 300.)]
+    dpi: f32,
+
+    /// Dithering method
+    #[arg(long, value_enum, default_value_t = Dither::FloydSteinberg)]
+    dither: Dither,
+
+    /// File format. Defaults to the output's extension, or PNG.
+    #[arg(long, value_enum)]
+    format: Option<RasterFormat>,
+
+    #[command(flatten)]
+    config: SinusoidShadingConfig,
+}
+
+/// Dither the input image at the physical output size and write it as a
+/// 1-bit bitmap.
+pub fn run(args: RasterArgs) -> Result<(), ImageProcessError> {
+    let format = args.format.unwrap_or_else(|| {
+        let extension = args.output.as_ref().and_then(|path| path.extension());
+        match extension.and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("bmp") => RasterFormat::Bmp,
+            _ => RasterFormat::Png,
+        }
+    });
+    let out_path = match args.output {
+        Some(path) => path,
+        None => {
+            let template = format!("{{stem}}_raster.{}", format);
+            batch::default_output(&args.input, &template, &args.config)
+        }
+    };
+
+    let img = batch::load_image(&args.input)?;
+    let dithered = img2laser::dither_image(&img, &args.config, args.dpi, args.dither);
+    batch::write_output(&out_path, |w| {
+        img2laser::write_bitmap(&dithered, args.dpi, format, w)
+    })?;
+
+    Ok(())
+}
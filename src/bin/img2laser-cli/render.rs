@@ -0,0 +1,228 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{ArgMatches, Args};
+
+use rayon::prelude::*;
+
+use img2laser::{MachineProfile, OutputFormat, SinusoidShadingConfig};
+
+use crate::progress::ProgressBar;
+use crate::{batch, watch};
+
+#[derive(Args, Debug)]
+/// Options for the `render` subcommand
+pub struct RenderArgs {
+    /// Input images. Each may be a file, a directory, a glob pattern or `-` to
+    /// read a single image from stdin.
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// Output SVG path, or `-` for stdout. Only valid with a single input
+    /// image.
+    #[arg(short, long, conflicts_with = "out_dir")]
+    output: Option<PathBuf>,
+
+    /// Directory to write output files to. Defaults to the directory of each
+    /// input image.
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+
+    /// Output file name template. `{stem}` is the input file name without its
+    /// extension and any shading option can be used, e.g. `{stem}_{lines}l.svg`.
+    /// Defaults to `{stem}.svg`, or `{stem}.gcode` for G-code.
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Machine profile file, or the name of a built-in profile: `epilog-zing`,
+    /// `universal-vls`, `glowforge` or `grbl`. The profile sets the output
+    /// format and stroke, and a warning is shown if the output is larger than
+    /// the bed.
+    #[arg(long)]
+    machine: Option<String>,
+
+    /// Output format. Defaults to the machine's preferred format, or SVG.
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Number of images to process in parallel. Defaults to the number of CPU
+    /// cores.
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Preset file with one `field = value` shading option per line. Values in
+    /// the preset override the shading options given on the command line and
+    /// the machine profile.
+    /// Built-in presets for laser stroke conventions can be used by name:
+    /// `default`, `hairline` (Epilog/Universal), `lightburn` and `glowforge`.
+    #[arg(long)]
+    preset: Option<PathBuf>,
+
+    /// Keep running and render again whenever the input image or the preset
+    /// changes. Requires a single input image and an output file.
+    #[arg(long)]
+    watch: bool,
+
+    #[command(flatten)]
+    config: SinusoidShadingConfig,
+}
+
+/// Render every input image to an SVG.
+///
+/// # Arguments
+/// * `cli` - The options.
+/// * `matches` - The matches the options were read from, to tell which
+///   options were given.
+pub fn run(cli: RenderArgs, matches: &ArgMatches) -> ExitCode {
+    let mut machine = match &cli.machine {
+        Some(name) => match batch::load_machine(name) {
+            Ok(machine) => machine,
+            Err(e) => {
+                eprintln!("error: {}: {}", name, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => MachineProfile::default(),
+    };
+    if let Some(format) = cli.format {
+        machine.format = format;
+    }
+
+    let name = cli
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("{{stem}}.{}", machine.format.extension()));
+    if let Err(e) = batch::validate_template(&name) {
+        eprintln!("error: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    // The machine's conventions fill in the options that weren't given on the
+    // command line
+    let mut base_config = cli.config.clone();
+    machine.apply(&mut base_config, |field| batch::given(matches, field));
+
+    let mut config = base_config.clone();
+    if let Some(preset) = &cli.preset {
+        if let Err(e) = batch::load_preset(preset, &mut config) {
+            eprintln!("error: {}: {}", preset.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let Some(warning) = machine.check_fits(&config) {
+        eprintln!("warning: {}", warning);
+    }
+
+    let (inputs, mut errors) = batch::expand_inputs(&cli.inputs);
+
+    let jobs = match &cli.output {
+        // A single explicit output path
+        Some(output) => {
+            // An input that couldn't be expanded is the real problem, not
+            // the number of inputs
+            if !errors.is_empty() {
+                for (input, e) in &errors {
+                    eprintln!("error: {}: {}", input, e);
+                }
+                return ExitCode::FAILURE;
+            }
+            if inputs.len() != 1 {
+                eprintln!("error: --output requires exactly one input image");
+                return ExitCode::FAILURE;
+            }
+            vec![batch::Job {
+                input: inputs[0].clone(),
+                output: output.clone(),
+            }]
+        }
+        None => {
+            let (jobs, mut plan_errors) =
+                batch::plan_jobs(inputs, cli.out_dir.as_deref(), &name, &config);
+            errors.append(&mut plan_errors);
+            jobs
+        }
+    };
+
+    if cli.watch {
+        let watchable = jobs.len() == 1
+            && errors.is_empty()
+            && !batch::is_stdio(&jobs[0].input)
+            && !batch::is_stdio(&jobs[0].output);
+        if !watchable {
+            eprintln!("error: --watch requires exactly one input image file and output file");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let Some(dir) = &cli.out_dir {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            eprintln!("error: could not create {}: {}", dir.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // The preset is read again on every change, so start from the options
+    // before the preset rather than `config`.
+    if cli.watch {
+        watch::run(&jobs[0], &base_config, &machine, cli.preset.as_deref());
+    }
+
+    let pool = match rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.unwrap_or(0))
+        .build()
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Process every image. A failure is reported and counted, but doesn't stop
+    // the rest of the batch.
+    let progress = ProgressBar::new(jobs.len());
+    let failed = pool.install(|| {
+        jobs.par_iter()
+            .enumerate()
+            .filter(|(i, job)| {
+                let result = batch::run_job(job, &config, &machine, |done, total| {
+                    progress.update(*i, done, total)
+                });
+                match result {
+                    Ok(written) => {
+                        let written: Vec<_> =
+                            written.iter().map(|p| p.display().to_string()).collect();
+                        progress.println(&format!(
+                            "{} -> {}",
+                            job.input.display(),
+                            written.join(", ")
+                        ));
+                        false
+                    }
+                    Err(e) => {
+                        progress.println(&format!("error: {}: {}", job.input.display(), e));
+                        true
+                    }
+                }
+            })
+            .count()
+    });
+    progress.finish();
+
+    for (input, e) in &errors {
+        eprintln!("error: {}: {}", input, e);
+    }
+
+    let succeeded = jobs.len() - failed;
+    let failed = failed + errors.len();
+    if succeeded + failed > 1 {
+        eprintln!("{} succeeded, {} failed", succeeded, failed);
+    }
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
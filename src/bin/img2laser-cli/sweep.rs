@@ -0,0 +1,68 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use clap::Args;
+
+use image::ImageOutputFormat;
+
+use img2laser::{ImageProcessError, SinusoidShadingConfig, Sweep};
+
+use crate::batch;
+
+#[derive(Args, Debug)]
+/// Options for the `sweep` subcommand
+pub struct SweepArgs {
+    /// Input image path, or `-` to read from stdin
+    input: PathBuf,
+
+    /// Output path, or `-` for stdout. A `.png` extension writes a bitmap,
+    /// anything else an SVG.
+    #[arg(short, long, default_value = "sweep.svg")]
+    output: PathBuf,
+
+    /// Option varied across columns, e.g. `lines=32,64,96`
+    #[arg(long, value_name = "FIELD=VALUES")]
+    x: Sweep,
+
+    /// Option varied across rows, e.g. `max_freq=1,2,3`
+    #[arg(long, value_name = "FIELD=VALUES")]
+    y: Option<Sweep>,
+
+    /// Output pixels per SVG unit, for PNG output
+    #[arg(long, default_value_t = 1.)]
+    scale: f32,
+
+    #[command(flatten)]
+    config: SinusoidShadingConfig,
+}
+
+/// Render a grid of the input image with different settings.
+pub fn run(args: SweepArgs) -> Result<(), ImageProcessError> {
+    let img = batch::load_image(&args.input)?;
+
+    let is_png = args
+        .output
+        .extension()
+        .map(|e| e.eq_ignore_ascii_case("png"))
+        .unwrap_or(false);
+
+    if is_png {
+        let sheet = img2laser::contact_sheet_preview(
+            &img,
+            &args.config,
+            &args.x,
+            args.y.as_ref(),
+            args.scale,
+        );
+
+        // PNG encoding needs a seekable writer, so encode in memory first
+        let mut png = Vec::new();
+        sheet.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+        batch::write_output(&args.output, |w| w.write_all(&png))?;
+    } else {
+        let sheet = img2laser::contact_sheet(&img, &args.config, &args.x, args.y.as_ref());
+        batch::write_output(&args.output, |w| svg::write(w, &sheet))?;
+    }
+
+    Ok(())
+}
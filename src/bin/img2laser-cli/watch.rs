@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use img2laser::{ImageProcessError, MachineProfile, SinusoidShadingConfig};
+
+use crate::batch::{self, Job};
+
+/// How often the watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the watched files must stay unchanged before rendering. Editors
+/// often save in several steps, and this avoids rendering half-written files.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Render `job`, then render it again every time the input image or preset
+/// changes. Runs until the process is stopped.
+///
+/// # Arguments
+/// * `job` - The input image and output file.
+/// * `config` - Shading options from the command line.
+/// * `machine` - The machine, which decides the output format.
+/// * `preset` - Optional preset file, applied on top of `config` on every
+///   render.
+pub fn run(
+    job: &Job,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    preset: Option<&Path>,
+) -> ! {
+    let mut watched = vec![job.input.clone()];
+    watched.extend(preset.map(Path::to_path_buf));
+
+    eprintln!("watching {} (Ctrl-C to stop)", display_list(&watched));
+
+    let mut last_seen = modified_times(&watched);
+    render(job, config, machine, preset);
+
+    // Time of the most recent change that hasn't been rendered yet
+    let mut pending: Option<Instant> = None;
+
+    loop {
+        thread::sleep(POLL_INTERVAL);
+
+        let seen = modified_times(&watched);
+        if seen != last_seen {
+            last_seen = seen;
+            pending = Some(Instant::now());
+        }
+
+        if let Some(changed) = pending {
+            if changed.elapsed() >= DEBOUNCE {
+                pending = None;
+                render(job, config, machine, preset);
+            }
+        }
+    }
+}
+
+/// Render once and print how it went. Errors are printed rather than returned
+/// so that watching continues, e.g. after saving a broken preset.
+fn render(
+    job: &Job,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    preset: Option<&Path>,
+) {
+    let start = Instant::now();
+
+    match try_render(job, config, machine, preset) {
+        Ok(stats) => eprintln!("rendered in {} ms: {}", start.elapsed().as_millis(), stats),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
+
+/// Apply the preset, render and atomically replace the output file.
+///
+/// # Returns
+/// * A short description of the output.
+fn try_render(
+    job: &Job,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    preset: Option<&Path>,
+) -> Result<String, ImageProcessError> {
+    let mut config = config.clone();
+    if let Some(preset) = preset {
+        batch::load_preset(preset, &mut config)?;
+    }
+
+    let img = batch::load_image(&job.input)?;
+    let geometry = img2laser::shade(&img, &config);
+    let mut size = 0;
+    for output in &batch::write_outputs(&geometry, &config, machine)? {
+        write_atomic(&output.path(&job.output), &output.data)?;
+        size += output.data.len();
+    }
+
+    // Counted from the geometry, as every method draws different shapes
+    let layers = &geometry.layers;
+    let paths: usize = layers.iter().map(|layer| layer.subpaths.len()).sum();
+    let points: usize = layers
+        .iter()
+        .flat_map(|layer| &layer.subpaths)
+        .map(|subpath| subpath.segments.len() + 1)
+        .sum();
+    Ok(format!(
+        "{} layers, {} paths, {} points, {} KiB",
+        layers.len(),
+        paths,
+        points,
+        size / 1024
+    ))
+}
+
+/// Write `data` to a temporary file next to `path`, then rename it into place.
+///
+/// Programs viewing the output (e.g. a browser or Inkscape) never see a
+/// partially written file this way.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Modification time of each file. Missing files are `None`.
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn display_list(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
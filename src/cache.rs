@@ -0,0 +1,195 @@
+use image::{DynamicImage, GrayImage};
+
+use ndarray::Array2;
+
+use crate::crosshatch::Crosshatch;
+use crate::geometry::{Geometry, Layer};
+use crate::method::shade_with_progress;
+use crate::output::geometry_document;
+use crate::parallel;
+use crate::separation::Separation;
+use crate::sinusoid::{
+    average_rows, make_line, place_line, FrequencyScale, Tracker, SINUSOID_LAYER,
+};
+use crate::{CancellationToken, ImageProcessError};
+use crate::{Method, SinusoidShadingConfig};
+
+/// Settings the row averages depend on
+#[derive(PartialEq)]
+struct AveragesKey {
+    lines: usize,
+    gamma: f32,
+}
+
+/// Settings the sine waves depend on, on top of the averages
+#[derive(PartialEq)]
+struct WavesKey {
+    sample_freq: f32,
+    min_freq: f32,
+    max_freq: f32,
+}
+
+/// Intermediate results of `process_image`, kept between renders.
+///
+/// The web app renders the SVG again every time a setting changes. Each stage
+/// here is only recomputed when a setting it depends on has changed, so e.g.
+/// moving the amplitude slider only redoes the final geometry.
+pub struct ShadingCache {
+    /// The image, for renders that don't use the stages below
+    image: DynamicImage,
+
+    /// Grayscale version of the image
+    luma: GrayImage,
+
+    /// Row averages from `average_rows`
+    averages: Option<(AveragesKey, Array2<u8>)>,
+
+    /// Unscaled sine waves from `make_line`, one per row
+    waves: Option<(WavesKey, Vec<Vec<f32>>)>,
+
+    /// The finished SVG and the configuration it was made with
+    svg: Option<(SinusoidShadingConfig, String)>,
+}
+
+impl ShadingCache {
+    /// Create a cache for an image.
+    pub fn new(img: &DynamicImage) -> Self {
+        ShadingCache {
+            image: img.clone(),
+            luma: img.to_luma8(),
+            averages: None,
+            waves: None,
+            svg: None,
+        }
+    }
+
+    /// Replace the image, discarding everything computed from the old one.
+    pub fn set_image(&mut self, img: &DynamicImage) {
+        *self = Self::new(img);
+    }
+
+    /// Get the SVG markup for the image, as `process_image` would create it.
+    ///
+    /// # Arguments
+    /// * `config` - The configuration struct.
+    ///
+    /// # Returns
+    /// * The SVG document as a string.
+    pub fn render(&mut self, config: &SinusoidShadingConfig) -> &str {
+        self.render_with_progress(config, |_, _| {}, &CancellationToken::new())
+            .expect("a new token is never cancelled")
+    }
+
+    /// Get the SVG markup for the image, as `process_image_with_progress`
+    /// would create it.
+    ///
+    /// Stages that were finished before the job was cancelled are kept, so
+    /// the next render doesn't have to redo them.
+    ///
+    /// # Arguments
+    /// * `config` - The configuration struct.
+    /// * `progress` - Called with the number of sinusoids done and the total,
+    ///   counting only the stages that aren't cached.
+    /// * `cancel` - Stops the job early when cancelled.
+    ///
+    /// # Returns
+    /// * The SVG document as a string, or `ImageProcessError::Cancelled`.
+    pub fn render_with_progress(
+        &mut self,
+        config: &SinusoidShadingConfig,
+        progress: impl Fn(usize, usize) + Sync,
+        cancel: &CancellationToken,
+    ) -> Result<&str, ImageProcessError> {
+        let averages_key = AveragesKey {
+            lines: config.line_count(),
+            gamma: config.gamma,
+        };
+        let waves_key = WavesKey {
+            sample_freq: config.sample_freq,
+            min_freq: config.min_freq,
+            max_freq: config.max_freq,
+        };
+
+        // Halftones and colour separations don't use the sinusoid stages, so
+        // only the finished SVG is kept
+        if config.method != Method::Sinusoid || config.separation != Separation::None {
+            if !matches!(&self.svg, Some((key, _)) if key == config) {
+                let geometry = shade_with_progress(&self.image, config, progress, cancel)?;
+                let svg = geometry_document(&geometry, config).to_string();
+                self.svg = Some((config.clone(), svg));
+            }
+
+            return Ok(&self.svg.as_ref().expect("SVG was just rendered").1);
+        }
+
+        // Every stage depends on the ones before it, so once a stage is stale
+        // all of the following ones are too.
+        if !matches!(&self.averages, Some((key, _)) if *key == averages_key) {
+            let avgs = average_rows(&self.luma, config);
+            self.averages = Some((averages_key, avgs));
+            self.waves = None;
+            self.svg = None;
+        }
+
+        if !matches!(&self.svg, Some((key, _)) if key == config) {
+            let avgs = &self.averages.as_ref().expect("averages were computed").1;
+            let scale = FrequencyScale::new(avgs, config);
+
+            // Hatch layers aren't cached, since they depend on nearly every
+            // setting anyway
+            let hatch = (!config.crosshatch.is_empty())
+                .then(|| Crosshatch::new(&self.luma, &scale, config));
+            let hatch_lines = hatch.as_ref().map_or(0, Crosshatch::total_line_count);
+
+            let waves_stale = !matches!(&self.waves, Some((key, _)) if *key == waves_key);
+            let stages = if waves_stale { 2 } else { 1 };
+            let tracker = Tracker::new(&progress, cancel, stages * avgs.nrows() + hatch_lines);
+
+            if waves_stale {
+                let waves = each_row(avgs.nrows(), &tracker, |yi| {
+                    make_line(avgs.row(yi), &scale, config.sample_freq).collect()
+                })?;
+                self.waves = Some((waves_key, waves));
+            }
+
+            let waves = &self.waves.as_ref().expect("waves were just computed").1;
+            let lines = each_row(avgs.nrows(), &tracker, |yi| {
+                place_line(yi, avgs.row(yi), waves[yi].iter().copied(), config)
+            })?;
+            let mut layers = vec![Layer::from_subpaths(SINUSOID_LAYER, lines)];
+
+            if let Some(hatch) = hatch {
+                layers.extend(hatch.layers(&tracker).ok_or(ImageProcessError::Cancelled)?);
+            }
+
+            let svg = geometry_document(&Geometry { layers }, config).to_string();
+            self.svg = Some((config.clone(), svg));
+        }
+
+        Ok(&self.svg.as_ref().expect("SVG was just rendered").1)
+    }
+}
+
+/// Run `f` for every row, in parallel where possible.
+///
+/// # Returns
+/// * The results in row order, or `ImageProcessError::Cancelled`. Rows that
+///   have already started are finished, the rest are skipped.
+fn each_row<T: Send>(
+    rows: usize,
+    tracker: &Tracker,
+    f: impl Fn(usize) -> T + Sync,
+) -> Result<Vec<T>, ImageProcessError> {
+    parallel::map_range(rows, |yi| {
+        if tracker.cancelled() {
+            return None;
+        }
+
+        let result = f(yi);
+        tracker.line_done();
+        Some(result)
+    })
+    .into_iter()
+    .collect::<Option<Vec<_>>>()
+    .ok_or(ImageProcessError::Cancelled)
+}
@@ -0,0 +1,168 @@
+use ndarray::Array1;
+
+use crate::font;
+use crate::geometry::{Geometry, Layer, Subpath, Vertex};
+use crate::sinusoid::{make_line, place_line, FrequencyScale};
+use crate::SinusoidShadingConfig;
+
+/// The settings tried on a material test card.
+///
+/// The card has a swatch for every combination: one column per `max_freqs`
+/// value, and one row per pair of `amplitudes` and `lines` values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestCard {
+    /// Grey level of every swatch, from 0 (black) to 255 (white)
+    pub grey: u8,
+
+    /// Values of `SinusoidShadingConfig::max_freq`
+    pub max_freqs: Vec<f32>,
+
+    /// Values of `SinusoidShadingConfig::amplitude`
+    pub amplitudes: Vec<f32>,
+
+    /// Values of `SinusoidShadingConfig::lines`
+    pub lines: Vec<usize>,
+}
+
+impl TestCard {
+    /// A card with a single black swatch, using the settings of `config`.
+    pub fn new(config: &SinusoidShadingConfig) -> Self {
+        TestCard {
+            grey: 0,
+            max_freqs: vec![config.max_freq],
+            amplitudes: vec![config.amplitude],
+            lines: vec![config.line_count()],
+        }
+    }
+}
+
+/// Lay out a material test card: a labelled grid of swatches of one grey
+/// level, each shaded with different settings.
+///
+/// Burning the card on a new material shows which settings give the tone
+/// wanted. Every swatch is shaded as that grey would be in an image rendered
+/// with the swatch's settings at `config.width` by `config.height`, with one
+/// image pixel per drawing unit. That is, its lines are
+/// `config.height / lines` apart and the frequency is scaled between
+/// `config.min_freq` for white and `max_freq` for black.
+///
+/// # Arguments
+/// * `card` - The settings to try.
+/// * `config` - The configuration shared by every swatch. The card fills
+///   `config.width` by `config.height`.
+///
+/// # Returns
+/// * The swatches, followed by a layer of single stroke labels.
+pub fn test_card(card: &TestCard, config: &SinusoidShadingConfig) -> Geometry {
+    let (width, height) = (config.width as f32, config.height as f32);
+
+    let rows: Vec<(f32, usize)> = card
+        .amplitudes
+        .iter()
+        .flat_map(|&amplitude| card.lines.iter().map(move |&lines| (amplitude, lines)))
+        .collect();
+    let row_labels: Vec<String> = rows
+        .iter()
+        .map(|(amplitude, lines)| format!("amplitude={} lines={}", amplitude, lines))
+        .collect();
+
+    // Labels are sized relative to the card, with the row labels in a column
+    // on the left and the column labels in a strip along the top
+    let label_size = (width.min(height) / 40.).max(2.);
+    let gap = label_size;
+    let label_width = row_labels
+        .iter()
+        .map(|label| font::text_width(label, label_size))
+        .fold(0., f32::max)
+        .min(width / 3.);
+    let grid_left = gap + label_width + gap;
+    let grid_top = gap + label_size + gap;
+
+    let pitch_x = (width - grid_left) / card.max_freqs.len().max(1) as f32;
+    let pitch_y = (height - grid_top) / rows.len().max(1) as f32;
+    let (swatch_width, swatch_height) = (pitch_x - gap, pitch_y - gap);
+
+    let mut swatches = Vec::new();
+    let mut labels = Vec::new();
+    let mut label = |text: &str, x: f32, y: f32, fit: f32| {
+        // Shrink labels that don't fit their space
+        let size = label_size.min(label_size * fit / font::text_width(text, label_size));
+        labels.extend(font::text_paths(text, x, y, size));
+    };
+
+    label(&format!("grey={}", card.grey), gap, gap, label_width);
+    for (col, &max_freq) in card.max_freqs.iter().enumerate() {
+        let left = grid_left + col as f32 * pitch_x;
+        label(&format!("max_freq={}", max_freq), left, gap, swatch_width);
+
+        for (row, &(amplitude, lines)) in rows.iter().enumerate() {
+            let top = grid_top + row as f32 * pitch_y;
+            if col == 0 {
+                label(&row_labels[row], gap, top, label_width);
+            }
+
+            let swatch_config = SinusoidShadingConfig {
+                max_freq,
+                amplitude,
+                lines,
+                lines_per_mm: None,
+                ..config.clone()
+            };
+            swatches.extend(swatch(
+                card.grey,
+                (left, top),
+                (swatch_width, swatch_height),
+                &swatch_config,
+            ));
+        }
+    }
+
+    Geometry {
+        layers: vec![
+            Layer::from_subpaths("swatches", swatches),
+            Layer::new("labels", labels),
+        ],
+    }
+}
+
+/// Shade one swatch of uniform grey with `make_line` and `place_line`.
+///
+/// The swatch is drawn as an image one pixel per drawing unit wide, of rows
+/// of `grey`, then moved into place. The lines keep the spacing of the whole
+/// card, `config.height / lines`, so as many fit as the swatch has room for.
+///
+/// # Arguments
+/// * `grey` - The grey level.
+/// * `origin` - Top left corner of the swatch.
+/// * `size` - Width and height of the swatch.
+/// * `config` - The settings for the swatch.
+fn swatch(
+    grey: u8,
+    (left, top): (f32, f32),
+    (width, height): (f32, f32),
+    config: &SinusoidShadingConfig,
+) -> Vec<Subpath> {
+    let spacing = config.height as f32 / config.line_count() as f32;
+    let rows = (height / spacing).floor() as usize;
+    let row = Array1::from_elem((width.floor() as usize).max(1), grey);
+
+    // The scale runs from white to black rather than over the swatch, which
+    // is a single grey
+    let scale = FrequencyScale::from_range(Some(u8::MAX), Some(0), config);
+    let swatch_config = SinusoidShadingConfig {
+        width: row.len(),
+        ..config.clone()
+    };
+
+    (0..rows)
+        .filter_map(|yi| {
+            let wave = make_line(row.view(), &scale, config.sample_freq);
+            let line = place_line(yi, row.view(), wave, &swatch_config);
+            Subpath::polyline(line.vertices().map(|vertex| Vertex {
+                x: vertex.x + left,
+                y: vertex.y + top,
+                ..vertex
+            }))
+        })
+        .collect()
+}
@@ -1,22 +1,59 @@
 #![allow(non_snake_case)]
-use crate::SinusoidShadingConfig;
+use std::io::Cursor;
+
+use crate::{BackgroundShader, SinusoidShadingConfig};
+use base64::{engine::general_purpose, Engine as _};
 use dioxus::prelude::*;
-use image::{DynamicImage, EncodableLayout, GenericImageView};
+use image::{DynamicImage, EncodableLayout, GenericImageView, ImageOutputFormat};
 
 const MAX_DIM: u32 = 1024;
 
+/// The original image as a base64 data URL, for displaying next to the SVG.
+///
+/// Encoding is slow for large images, so this is only done once per upload
+/// rather than on every render.
+pub struct ImagePreview(pub String);
+
+impl ImagePreview {
+    /// Encode an image as a PNG data URL.
+    pub fn new(img: &DynamicImage) -> Self {
+        let mut image_data: Vec<u8> = Vec::new();
+        img.write_to(&mut Cursor::new(&mut image_data), ImageOutputFormat::Png)
+            .unwrap();
+        let res_base64 = general_purpose::STANDARD.encode(image_data);
+        ImagePreview(format!("data:image/png;base64,{}", res_base64))
+    }
+}
+
 #[inline_props]
 pub fn SinusoidSvg(cx: Scope) -> Element {
-    let img = use_shared_state::<DynamicImage>(cx).unwrap();
+    let shader = use_shared_state::<BackgroundShader>(cx).unwrap();
     let config = use_shared_state::<SinusoidShadingConfig>(cx).unwrap();
 
-    // Process image
-    let svg_img = crate::process_image(&img.read(), &config.read());
+    // Process image in the background. Requesting the same settings again
+    // does nothing, and a request isn't a change anyone needs to re-render
+    // for. The shader re-renders this component when the result arrives.
+    let mut shader = shader.write_silent();
+    shader.set_listener(cx.schedule_update());
+    shader.request(&config.read());
+
+    // Keep showing the previous result, faded, until the new one is ready
+    let (opacity, progress) = if shader.busy() {
+        (0.5, "block")
+    } else {
+        (1., "none")
+    };
+    let svg_img = shader.svg();
 
     render! {
+            progress {
+                id: "shading-progress",
+                style: "display: {progress};",
+            },
             div {
                 id: "svg-container",
-                dangerous_inner_html: "{svg_img.to_string()}",
+                style: "opacity: {opacity};",
+                dangerous_inner_html: "{svg_img}",
         }
     }
 }
@@ -62,9 +99,38 @@ pub fn SliderInput<'a, T: std::fmt::Display>(
     }
 }
 
+#[inline_props]
+pub fn SelectInput<'a>(
+    cx: Scope<'a>,
+    id: String,
+    label: String,
+    options: Vec<String>,
+    value: String,
+    on_input: EventHandler<'a, FormEvent>,
+) -> Element {
+    render! {
+        label {
+            r#for: "{id}",
+            "{label}"
+        },
+        select {
+            id: "{id}",
+            onchange: move |event| on_input.call(event),
+            options.iter().map(|option| rsx! {
+                option {
+                    value: "{option}",
+                    selected: option == value,
+                    "{option}"
+                }
+            })
+        }
+    }
+}
+
 #[inline_props]
 pub fn FileInput(cx: Scope, id: String, label: String) -> Element {
-    let img = use_shared_state::<DynamicImage>(cx).unwrap();
+    let shader = use_shared_state::<BackgroundShader>(cx).unwrap();
+    let preview = use_shared_state::<ImagePreview>(cx).unwrap();
     let config = use_shared_state::<SinusoidShadingConfig>(cx).unwrap();
 
     render! {
@@ -80,7 +146,7 @@ pub fn FileInput(cx: Scope, id: String, label: String) -> Element {
             accept: ".png,.jpg,.jpeg,.gif,.tif,.tiff",
             // See example: https://github.com/DioxusLabs/dioxus/blob/master/examples/file_upload.rs
             onchange: |event| {
-                to_owned![img, config];
+                to_owned![shader, preview, config];
                 async move {
                     if let Some(file_engine) = &event.files {
                         let files = file_engine.files();
@@ -107,7 +173,8 @@ pub fn FileInput(cx: Scope, id: String, label: String) -> Element {
 
                         let (width, height) = tmp_img.dimensions();
 
-                        img.with_mut(|i| *i = tmp_img);
+                        preview.with_mut(|p| *p = ImagePreview::new(&tmp_img));
+                        shader.with_mut(|s| s.set_image(&tmp_img));
                         // file.set(file_contents);
 
                         // Update dimensions
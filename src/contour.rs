@@ -0,0 +1,503 @@
+use std::collections::{HashMap, VecDeque};
+
+use clap::ValueEnum;
+
+use image::{DynamicImage, GrayImage};
+
+use crate::area::AreaAverages;
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::sinusoid::{darkness, Tracker};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Grid cells per row of the output. Contours are traced on a grid of
+/// image averages, which smooths away noise finer than the rows.
+const CELLS_PER_ROW: usize = 2;
+
+/// How the contour levels are spread between white and black
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ContourSpacing {
+    /// Evenly spaced darkness levels
+    #[default]
+    Even,
+
+    /// Levels follow the image's tone curve, so each band between two
+    /// contours covers about the same area
+    Tone,
+}
+
+value_enum_strings!(ContourSpacing);
+
+/// What is drawn between the contours
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ContourFill {
+    /// Only the contours
+    #[default]
+    None,
+
+    /// Horizontal hatching, denser in darker bands
+    Hatch,
+}
+
+value_enum_strings!(ContourFill);
+
+/// Trace the iso-darkness contours of a grayscale image.
+///
+/// # Returns
+/// * A layer of contours, followed by a layer of hatching if
+///   `config.contour_fill` asks for it, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_contours(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let contours = Contours::new(&img.to_luma8(), config);
+    let tracker = Tracker::new(progress, cancel, contours.line_count());
+
+    let layers = contours
+        .layers(&tracker)
+        .ok_or(ImageProcessError::Cancelled)?;
+    Ok(layers
+        .into_iter()
+        .zip(["contours", "contour-hatch"])
+        .map(|(subpaths, name)| Layer::from_subpaths(name, subpaths))
+        .collect())
+}
+
+/// The darkness of an image on a grid, and the levels to trace it at.
+///
+/// Contours are found with marching squares: every square of four grid
+/// points that has points on both sides of a level gets one or two
+/// segments, crossing its edges where the darkness reaches the level. The
+/// segments of neighbouring squares share their crossings, so they are
+/// joined into polylines by following the shared edges.
+pub(crate) struct Contours<'a> {
+    config: &'a SinusoidShadingConfig,
+
+    /// Number of grid points across and down
+    cols: usize,
+    rows: usize,
+
+    /// Darkness of each grid point, from 0 to 1, row by row
+    values: Vec<f32>,
+
+    /// Contour levels in increasing darkness
+    levels: Vec<f32>,
+}
+
+/// An edge between two neighbouring grid points, by the point at its top
+/// or left end, and whether it is horizontal
+type Edge = (usize, usize, bool);
+
+impl<'a> Contours<'a> {
+    /// Sample an image and choose the levels.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `config` - The configuration struct.
+    pub(crate) fn new(luma: &GrayImage, config: &'a SinusoidShadingConfig) -> Self {
+        let area = AreaAverages::new(luma, config.gamma);
+        let (img_width, img_height) = area.dimensions();
+
+        // Square cells, `CELLS_PER_ROW` to a row of the output
+        let cell = config.height as f32 / (config.line_count() * CELLS_PER_ROW) as f32;
+        let cols = ((config.width as f32 / cell).round() as usize).max(2);
+        let rows = ((config.height as f32 / cell).round() as usize).max(2);
+
+        let (px_x, px_y) = (
+            img_width as f32 / cols as f32,
+            img_height as f32 / rows as f32,
+        );
+        let values: Vec<f32> = (0..rows * cols)
+            .map(|i| {
+                let (col, row) = ((i % cols) as f32, (i / cols) as f32);
+                let value =
+                    area.average(col * px_x, row * px_y, (col + 1.) * px_x, (row + 1.) * px_y);
+                darkness(value)
+            })
+            .collect();
+
+        let levels = levels(&values, config);
+        Contours {
+            config,
+            cols,
+            rows,
+            values,
+            levels,
+        }
+    }
+
+    /// Number of steps reported to a `Tracker`: one per level, and one per
+    /// hatched band.
+    pub(crate) fn line_count(&self) -> usize {
+        match self.config.contour_fill {
+            ContourFill::None => self.levels.len(),
+            ContourFill::Hatch => 2 * self.levels.len(),
+        }
+    }
+
+    /// Trace the contours, and the hatching if there is any.
+    ///
+    /// # Returns
+    /// * The contours, followed by the hatching. `None` if it was cancelled.
+    pub(crate) fn layers(&self, tracker: &Tracker) -> Option<Vec<Vec<Subpath>>> {
+        let mut contours = Vec::new();
+        for &level in &self.levels {
+            if tracker.cancelled() {
+                return None;
+            }
+
+            contours.extend(self.contour(level));
+            tracker.line_done();
+        }
+
+        let mut layers = vec![contours];
+        if self.config.contour_fill == ContourFill::Hatch {
+            let mut hatching = Vec::new();
+            for band in 1..=self.levels.len() {
+                if tracker.cancelled() {
+                    return None;
+                }
+
+                hatching.extend(self.hatch(band));
+                tracker.line_done();
+            }
+            layers.push(hatching);
+        }
+
+        Some(layers)
+    }
+
+    /// Trace the contours of one level with marching squares.
+    ///
+    /// # Returns
+    /// * Contours that close on themselves as closed subpaths, and those that
+    ///   run off the edge of the output as open ones.
+    fn contour(&self, level: f32) -> Vec<Subpath> {
+        // Segments as pairs of crossed edges, and the segments on each edge
+        let mut segments: Vec<[Edge; 2]> = Vec::new();
+        let mut on_edge: HashMap<Edge, Vec<usize>> = HashMap::new();
+
+        for row in 0..self.rows - 1 {
+            for col in 0..self.cols - 1 {
+                for segment in self.square(col, row, level) {
+                    for edge in segment {
+                        on_edge.entry(edge).or_default().push(segments.len());
+                    }
+                    segments.push(segment);
+                }
+            }
+        }
+
+        // Join segments that share an edge, following each chain both ways
+        // from its first segment
+        let mut used = vec![false; segments.len()];
+        let mut paths = Vec::new();
+        for first in 0..segments.len() {
+            if used[first] {
+                continue;
+            }
+            used[first] = true;
+
+            let mut chain = VecDeque::from(segments[first]);
+            let mut closed = false;
+            for forward in [true, false] {
+                loop {
+                    let end = if forward { chain.back() } else { chain.front() };
+                    let end = *end.expect("chains start with two edges");
+                    let next = on_edge[&end].iter().copied().find(|&s| !used[s]);
+                    let Some(next) = next else { break };
+                    used[next] = true;
+
+                    let [a, b] = segments[next];
+                    let other = if a == end { b } else { a };
+                    if chain.front() == Some(&other) || chain.back() == Some(&other) {
+                        closed = true;
+                        break;
+                    }
+                    if forward {
+                        chain.push_back(other);
+                    } else {
+                        chain.push_front(other);
+                    }
+                }
+
+                if closed {
+                    break;
+                }
+            }
+
+            let points = chain.iter().map(|&edge| self.crossing(edge, level));
+            if let Some(path) = Subpath::polyline(points) {
+                paths.push(Subpath { closed, ..path });
+            }
+        }
+
+        paths
+    }
+
+    /// The segments of one square of the grid, by its top left point.
+    fn square(&self, col: usize, row: usize, level: f32) -> Vec<[Edge; 2]> {
+        let inside = |c: usize, r: usize| self.value(c, r) >= level;
+        let corners = [
+            inside(col, row),
+            inside(col + 1, row),
+            inside(col + 1, row + 1),
+            inside(col, row + 1),
+        ];
+
+        // Edges between the corners: top, right, bottom and left
+        let edges = [
+            (col, row, true),
+            (col + 1, row, false),
+            (col, row + 1, true),
+            (col, row, false),
+        ];
+        let crossed: Vec<Edge> = (0..4)
+            .filter(|&i| corners[i] != corners[(i + 1) % 4])
+            .map(|i| edges[i])
+            .collect();
+
+        match *crossed.as_slice() {
+            [a, b] => vec![[a, b]],
+            [top, right, bottom, left] => {
+                // A saddle, with opposite corners on the same side. The
+                // average of the square decides whether the inside corners
+                // are joined through the middle, cutting off the other two.
+                let middle = [(0, 0), (1, 0), (1, 1), (0, 1)]
+                    .map(|(c, r)| self.value(col + c, row + r))
+                    .iter()
+                    .sum::<f32>()
+                    / 4.;
+                if (middle >= level) == corners[0] {
+                    vec![[top, right], [bottom, left]]
+                } else {
+                    vec![[left, top], [right, bottom]]
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Where a level crosses an edge, interpolated between its ends.
+    fn crossing(&self, (col, row, horizontal): Edge, level: f32) -> Vertex {
+        let (col2, row2) = if horizontal {
+            (col + 1, row)
+        } else {
+            (col, row + 1)
+        };
+        let (a, b) = (self.value(col, row), self.value(col2, row2));
+        let t = if a != b { (level - a) / (b - a) } else { 0.5 };
+
+        let (x1, y1) = self.position(col, row);
+        let (x2, y2) = self.position(col2, row2);
+        Vertex::with_intensity(x1 + (x2 - x1) * t, y1 + (y2 - y1) * t, level)
+    }
+
+    /// Hatch one band between two levels with horizontal lines.
+    ///
+    /// Band `band` lies between levels `band - 1` and `band` (or past the
+    /// last level). The darkest band is hatched a row apart, and each lighter
+    /// band more sparsely in proportion, so the density follows the level.
+    fn hatch(&self, band: usize) -> Vec<Subpath> {
+        let low = self.levels[band - 1];
+        let high = self.levels.get(band).copied().unwrap_or(f32::INFINITY);
+        let row_height = self.config.height as f32 / self.config.line_count() as f32;
+        let spacing = row_height * self.levels.len() as f32 / band as f32;
+
+        // Darkness of the band, for the vertices
+        let intensity = band as f32 / self.levels.len() as f32;
+        let in_band = |v: f32| v >= low && v < high;
+
+        let mut lines = Vec::new();
+        let count = (self.config.height as f32 / spacing).ceil() as usize;
+        for i in 0..count {
+            let y = (i as f32 + 0.5) * spacing;
+
+            // Darkness along the line at each grid column
+            let samples: Vec<(f32, f32)> = (0..self.cols)
+                .map(|col| (self.position(col, 0).0, self.value_at_y(col, y)))
+                .collect();
+
+            let mut start = None;
+            for (k, &(x, v)) in samples.iter().enumerate() {
+                let inside = in_band(v);
+
+                // Where the band starts or ends between two samples
+                let edge = || {
+                    let (px, pv) = samples[k - 1];
+                    let level = if (pv < low) != (v < low) { low } else { high };
+                    px + (x - px) * ((level - pv) / (v - pv)).clamp(0., 1.)
+                };
+
+                match (start, inside) {
+                    (None, true) => start = Some(if k == 0 { 0. } else { edge() }),
+                    (Some(from), false) => {
+                        lines.push(self.hatch_line(from, edge(), y, intensity));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(from) = start {
+                lines.push(self.hatch_line(from, self.config.width as f32, y, intensity));
+            }
+        }
+
+        lines
+    }
+
+    fn hatch_line(&self, from: f32, to: f32, y: f32, intensity: f32) -> Subpath {
+        Subpath::polyline([
+            Vertex::with_intensity(from, y, intensity),
+            Vertex::with_intensity(to, y, intensity),
+        ])
+        .expect("two points")
+    }
+
+    fn value(&self, col: usize, row: usize) -> f32 {
+        self.values[row * self.cols + col]
+    }
+
+    /// Darkness at a grid column, interpolated between rows.
+    fn value_at_y(&self, col: usize, y: f32) -> f32 {
+        let cell = self.config.height as f32 / self.rows as f32;
+        let row = (y / cell - 0.5).clamp(0., (self.rows - 1) as f32);
+        let (above, t) = (row.floor() as usize, row.fract());
+        let below = (above + 1).min(self.rows - 1);
+        self.value(col, above) * (1. - t) + self.value(col, below) * t
+    }
+
+    /// Position of a grid point in output units, at the centre of its cell.
+    fn position(&self, col: usize, row: usize) -> (f32, f32) {
+        (
+            (col as f32 + 0.5) * self.config.width as f32 / self.cols as f32,
+            (row as f32 + 0.5) * self.config.height as f32 / self.rows as f32,
+        )
+    }
+}
+
+/// Contour levels for an image, in increasing darkness.
+///
+/// # Arguments
+/// * `values` - The darkness of each grid point.
+/// * `config` - The configuration struct, for the number and spacing.
+fn levels(values: &[f32], config: &SinusoidShadingConfig) -> Vec<f32> {
+    let count = config.contour_levels;
+    let mut levels: Vec<f32> = match config.contour_spacing {
+        ContourSpacing::Even => (1..=count).map(|k| k as f32 / (count + 1) as f32).collect(),
+        ContourSpacing::Tone => {
+            let mut sorted = values.to_vec();
+            sorted.sort_by(f32::total_cmp);
+            (1..=count)
+                .map(|k| sorted[(k * sorted.len() / (count + 1)).min(sorted.len() - 1)])
+                .collect()
+        }
+    };
+
+    // Contours at white would outline the whole image, and an image with
+    // few tones gives the same level more than once
+    levels.retain(|&level| level > 0.);
+    levels.dedup();
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    /// A 40 by 40 output with one grid point per image pixel.
+    fn config(levels: usize) -> SinusoidShadingConfig {
+        SinusoidShadingConfig {
+            width: 40,
+            height: 40,
+            lines: 20,
+            contour_levels: levels,
+            ..Default::default()
+        }
+    }
+
+    /// Black discs on white, by centre and radius.
+    fn discs(discs: &[((f32, f32), f32)]) -> GrayImage {
+        GrayImage::from_fn(40, 40, |x, y| {
+            let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+            let inside = discs
+                .iter()
+                .any(|&((cx, cy), r)| (x - cx).hypot(y - cy) < r);
+            Luma([if inside { 0 } else { 255 }])
+        })
+    }
+
+    #[test]
+    fn disc_gives_one_closed_loop_around_it() {
+        let config = config(1);
+        let contours = Contours::new(&discs(&[((20., 20.), 10.)]), &config);
+        let paths = contours.contour(0.5);
+
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].closed);
+        for vertex in paths[0].vertices() {
+            let radius = (vertex.x - 20.).hypot(vertex.y - 20.);
+            assert!((radius - 10.).abs() < 1., "{:?}", vertex);
+            assert_eq!(vertex.intensity, Some(0.5));
+        }
+    }
+
+    #[test]
+    fn separate_discs_give_separate_loops() {
+        let config = config(1);
+        let contours = Contours::new(&discs(&[((10., 10.), 5.), ((30., 28.), 6.)]), &config);
+        let paths = contours.contour(0.5);
+
+        assert_eq!(paths.len(), 2);
+        assert!(paths.iter().all(|path| path.closed));
+    }
+
+    #[test]
+    fn contour_off_the_edge_is_open() {
+        let config = config(1);
+        let contours = Contours::new(&discs(&[((0., 20.), 10.)]), &config);
+        let paths = contours.contour(0.5);
+
+        assert_eq!(paths.len(), 1);
+        assert!(!paths[0].closed);
+        let end = paths[0].segments.last().unwrap().end();
+        for point in [paths[0].start, end] {
+            assert!(point.x < 1., "{:?}", point);
+        }
+    }
+
+    #[test]
+    fn even_levels() {
+        let config = config(3);
+        assert_eq!(levels(&[0., 1.], &config), vec![0.25, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn tone_levels_skip_white_and_repeats() {
+        let config = SinusoidShadingConfig {
+            contour_spacing: ContourSpacing::Tone,
+            ..config(3)
+        };
+        assert_eq!(levels(&[0., 0., 1., 1., 1., 1.], &config), vec![1.]);
+    }
+
+    #[test]
+    fn black_band_is_hatched_across() {
+        let config = SinusoidShadingConfig {
+            contour_fill: ContourFill::Hatch,
+            ..config(1)
+        };
+        let contours = Contours::new(&GrayImage::from_pixel(40, 40, Luma([0])), &config);
+        let lines = contours.hatch(1);
+
+        // The darkest band is hatched a row apart
+        assert_eq!(lines.len(), 20);
+        for line in lines {
+            assert_eq!(line.start.x, 0.);
+            assert_eq!(line.segments[0].end().x, 40.);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::fmt;
+use std::str::FromStr;
+
+use image::GrayImage;
+
+use crate::area::AreaAverages;
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::parallel;
+use crate::sinusoid::{darkness, FrequencyScale, Tracker};
+use crate::SinusoidShadingConfig;
+
+/// Default darkness above which a hatch layer is drawn
+const THRESHOLD: f32 = 0.5;
+
+/// An extra set of sinusoids drawn over the horizontal ones, e.g. a second
+/// pass at 90° to deepen the blacks.
+///
+/// Written as `angle` or `angle:threshold`, e.g. `90` or `45:0.75`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HatchLayer {
+    /// Direction of the sinusoids in degrees, counter-clockwise from
+    /// horizontal
+    pub angle: f32,
+
+    /// Darkness, from 0 (white) to 1 (black), below which the layer lifts
+    /// the pen. Higher thresholds keep the layer to the darker tones.
+    pub threshold: f32,
+}
+
+impl fmt::Display for HatchLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.angle, self.threshold)
+    }
+}
+
+impl FromStr for HatchLayer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid hatch layer `{}`", s);
+
+        let (angle, threshold) = match s.trim().split_once(':') {
+            Some((angle, threshold)) => (angle, threshold.trim().parse().map_err(|_| invalid())?),
+            None => (s, THRESHOLD),
+        };
+        let angle = angle.trim().parse().map_err(|_| invalid())?;
+
+        Ok(HatchLayer { angle, threshold })
+    }
+}
+
+/// Traces hatch layers over an image.
+///
+/// The horizontal sinusoids can average whole bands of rows up front, but a
+/// rotated sinusoid crosses many rows. Instead, the average of the square
+/// under each point of a line is looked up as it is traced.
+pub(crate) struct Crosshatch<'a> {
+    config: &'a SinusoidShadingConfig,
+
+    /// The frequency scale of the horizontal sinusoids, so that the same tone
+    /// gives the same frequency in every layer
+    scale: &'a FrequencyScale,
+
+    /// The image to sample
+    area: AreaAverages,
+}
+
+impl<'a> Crosshatch<'a> {
+    /// Prepare an image for hatching.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `scale` - The frequency scale of the horizontal sinusoids.
+    /// * `config` - The configuration struct.
+    pub(crate) fn new(
+        luma: &GrayImage,
+        scale: &'a FrequencyScale,
+        config: &'a SinusoidShadingConfig,
+    ) -> Self {
+        Crosshatch {
+            config,
+            scale,
+            area: AreaAverages::new(luma, config.gamma),
+        }
+    }
+
+    /// Number of sinusoids in all of `config.crosshatch`, for progress
+    /// reporting.
+    pub(crate) fn total_line_count(&self) -> usize {
+        self.config
+            .crosshatch
+            .iter()
+            .map(|layer| self.line_count(layer))
+            .sum()
+    }
+
+    /// Trace every layer of `config.crosshatch`.
+    ///
+    /// # Returns
+    /// * The layers, named `hatch-1`, `hatch-2`, ... (drawn over the layer of
+    ///   horizontal sinusoids, `SINUSOID_LAYER`), or `None` if they were
+    ///   cancelled.
+    pub(crate) fn layers(&self, tracker: &Tracker) -> Option<Vec<Layer>> {
+        self.config
+            .crosshatch
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let lines = self.trace(layer, tracker)?;
+                Some(Layer::from_subpaths(format!("hatch-{}", i + 1), lines))
+            })
+            .collect()
+    }
+
+    /// Number of sinusoids in a layer.
+    pub(crate) fn line_count(&self, layer: &HatchLayer) -> usize {
+        let (_, normal) = directions(layer.angle);
+        let (extent, spacing) = self.extent(normal);
+        (2. * extent / spacing).ceil() as usize
+    }
+
+    /// Trace one layer.
+    ///
+    /// # Returns
+    /// * The subpaths of the layer, or `None` if it was cancelled. A
+    ///   sinusoid is split wherever it crosses tones lighter than the
+    ///   threshold.
+    pub(crate) fn trace(&self, layer: &HatchLayer, tracker: &Tracker) -> Option<Vec<Subpath>> {
+        let (direction, normal) = directions(layer.angle);
+        let (extent, spacing) = self.extent(normal);
+
+        let lines = parallel::map_range(self.line_count(layer), |k| {
+            if tracker.cancelled() {
+                return None;
+            }
+
+            // Offsets are measured from the centre, so the layer is symmetric
+            let offset = -extent + (k as f32 + 0.5) * spacing;
+            let lines = self.trace_line(offset, direction, normal, spacing, layer.threshold);
+
+            tracker.line_done();
+            Some(lines)
+        });
+
+        lines
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .map(|lines| lines.concat())
+    }
+
+    /// Half the distance the layer spans across its sinusoids, and the
+    /// spacing between them. The spacing matches the horizontal sinusoids.
+    fn extent(&self, normal: (f32, f32)) -> (f32, f32) {
+        let (width, height) = (self.config.width as f32, self.config.height as f32);
+        let extent = (normal.0.abs() * width + normal.1.abs() * height) / 2.;
+        let spacing = height / self.config.line_count() as f32;
+        (extent, spacing)
+    }
+
+    /// Trace the sinusoid `offset` away from the centre of the output.
+    fn trace_line(
+        &self,
+        offset: f32,
+        direction: (f32, f32),
+        normal: (f32, f32),
+        spacing: f32,
+        threshold: f32,
+    ) -> Vec<Subpath> {
+        let config = self.config;
+        let (width, height) = (config.width as f32, config.height as f32);
+        let fs = config.sample_freq;
+        let amp = config.amplitude * spacing;
+
+        // Centre line of the sinusoid, clipped to the output
+        let start = (
+            width / 2. + normal.0 * offset,
+            height / 2. + normal.1 * offset,
+        );
+        let Some((t_min, t_max)) = clip(start.0, direction.0, width)
+            .zip(clip(start.1, direction.1, height))
+            .map(|((a_min, a_max), (b_min, b_max))| (a_min.max(b_min), a_max.min(b_max)))
+            .filter(|(t_min, t_max)| t_min < t_max)
+        else {
+            return Vec::new();
+        };
+
+        // Step as far as the horizontal sinusoids do between samples
+        let (img_width, img_height) = self.area.dimensions();
+        let step = width / img_width as f32 / fs;
+        let samples = ((t_max - t_min) / step).ceil() as usize + 1;
+
+        // Image pixels per output unit, and the size of the averaged square
+        let (px_x, px_y) = (img_width as f32 / width, img_height as f32 / height);
+        let half = spacing / 2.;
+
+        let mut lines = Vec::new();
+        let mut current = Vec::new();
+        let mut phase = 0.;
+
+        for n in 0..samples {
+            let t = (t_min + n as f32 * step).min(t_max);
+            let (x, y) = (start.0 + direction.0 * t, start.1 + direction.1 * t);
+
+            let value = self.area.average(
+                (x - half) * px_x,
+                (y - half) * px_y,
+                (x + half) * px_x,
+                (y + half) * px_y,
+            );
+
+            // Phase keeps accumulating while the pen is up, so the sinusoid
+            // carries on where it would have been.
+            phase += self.scale.frequency(value);
+
+            let dark = darkness(value);
+            if dark >= threshold {
+                let wave = amp * (phase / fs).sin();
+                current.push(Vertex::with_intensity(
+                    x + normal.0 * wave,
+                    y + normal.1 * wave,
+                    dark,
+                ));
+            } else if !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+            }
+        }
+        lines.push(current);
+
+        // A single point draws nothing
+        lines
+            .into_iter()
+            .filter(|line| line.len() > 1)
+            .filter_map(Subpath::polyline)
+            .collect()
+    }
+}
+
+/// Unit vectors along and across lines at `angle` degrees. The y-axis
+/// points down, so the direction is flipped to keep angles counter-clockwise.
+pub(crate) fn directions(angle: f32) -> ((f32, f32), (f32, f32)) {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let direction = (cos, -sin);
+    let normal = (-direction.1, direction.0);
+    (direction, normal)
+}
+
+/// Range of `t` for which `start + direction * t` lies within `0..=size`.
+fn clip(start: f32, direction: f32, size: f32) -> Option<(f32, f32)> {
+    if direction.abs() < 1e-6 {
+        // Parallel to this axis, so either always inside or never
+        return (0. ..=size)
+            .contains(&start)
+            .then_some((f32::NEG_INFINITY, f32::INFINITY));
+    }
+
+    let a = -start / direction;
+    let b = (size - start) / direction;
+    Some((a.min(b), a.max(b)))
+}
@@ -0,0 +1,373 @@
+use clap::ValueEnum;
+
+use image::{DynamicImage, GrayImage};
+
+use crate::area::AreaAverages;
+use crate::flow::{wave_along, Modulation};
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::sinusoid::{average_rows, darkness, FrequencyScale, Tracker};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Spacing of the curve in white areas, in rows. The curve has to pass
+/// through them to stay one path, but does so sparsely.
+const LIGHTEST_SPACING: f32 = 8.;
+
+/// Which space-filling curve to follow
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Curve {
+    /// Splits each cell into 2 by 2
+    #[default]
+    Hilbert,
+
+    /// Splits each cell into 3 by 3
+    Peano,
+}
+
+value_enum_strings!(Curve);
+
+/// How the image's detail is added to the curve
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CurveDetail {
+    /// Subdivide the curve further where it is darker
+    #[default]
+    Subdivide,
+
+    /// Follow an evenly subdivided curve with a frequency modulated
+    /// sinusoid, as the rows of the sinusoid method
+    Sinusoid,
+}
+
+value_enum_strings!(CurveDetail);
+
+/// Draw a grayscale image as a single space-filling curve.
+///
+/// # Returns
+/// * A single layer with one subpath, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_curve(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let luma = img.to_luma8();
+    let scale = FrequencyScale::new(&average_rows(&luma, config), config);
+    let tracker = Tracker::new(progress, cancel, 1);
+
+    let path = SpaceFilling::new(&luma, &scale, config)
+        .trace(&tracker)
+        .ok_or(ImageProcessError::Cancelled)?;
+    Ok(vec![Layer::from_subpaths("curve", path)])
+}
+
+/// A space-filling curve over the output, shaped by an image.
+///
+/// The curve is built by recursively splitting the output into cells,
+/// visiting the children of each cell in the curve's order and turned so
+/// that each child starts next to where the previous one ended. The path
+/// joins the centres of the cells that aren't split. Cells can stop
+/// splitting at different depths and the path stays continuous, so darker
+/// areas can be split more finely than lighter ones.
+pub(crate) struct SpaceFilling<'a> {
+    config: &'a SinusoidShadingConfig,
+
+    /// The frequency scale, for `CurveDetail::Sinusoid`
+    scale: &'a FrequencyScale,
+
+    /// The image to sample
+    area: AreaAverages,
+
+    /// Closest spacing of the curve, the same as between the rows of the
+    /// sinusoid method
+    spacing: f32,
+}
+
+/// A cell of the curve, as a corner and the vectors along its two sides.
+/// The curve enters the cell near `origin`, and the sides' directions set
+/// which way it turns.
+#[derive(Clone, Copy)]
+struct Cell {
+    origin: (f32, f32),
+    across: (f32, f32),
+    along: (f32, f32),
+}
+
+impl Cell {
+    /// The point `(i, j)` of the way along each side.
+    fn at(&self, i: f32, j: f32) -> (f32, f32) {
+        (
+            self.origin.0 + self.across.0 * i + self.along.0 * j,
+            self.origin.1 + self.across.1 * i + self.along.1 * j,
+        )
+    }
+
+    /// A child cell, from `(i, j)` of the way along each side, with its
+    /// sides a fraction of the parent's (and possibly reversed).
+    fn child(&self, (i, j): (f32, f32), across: f32, along: f32) -> Cell {
+        Cell {
+            origin: self.at(i, j),
+            across: (self.across.0 * across, self.across.1 * across),
+            along: (self.along.0 * along, self.along.1 * along),
+        }
+    }
+
+    /// Length of the shorter side.
+    fn size(&self) -> f32 {
+        let across = self.across.0.hypot(self.across.1);
+        let along = self.along.0.hypot(self.along.1);
+        across.min(along)
+    }
+}
+
+impl<'a> SpaceFilling<'a> {
+    /// Prepare an image for tracing.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `scale` - The frequency scale.
+    /// * `config` - The configuration struct.
+    pub(crate) fn new(
+        luma: &GrayImage,
+        scale: &'a FrequencyScale,
+        config: &'a SinusoidShadingConfig,
+    ) -> Self {
+        SpaceFilling {
+            config,
+            scale,
+            area: AreaAverages::new(luma, config.gamma),
+            spacing: config.height as f32 / config.line_count() as f32,
+        }
+    }
+
+    /// Trace the curve.
+    ///
+    /// # Returns
+    /// * The curve as a single subpath, or `None` if it was cancelled.
+    pub(crate) fn trace(&self, tracker: &Tracker) -> Option<Vec<Subpath>> {
+        if tracker.cancelled() {
+            return None;
+        }
+
+        let (width, height) = (self.config.width as f32, self.config.height as f32);
+        let whole = Cell {
+            origin: (0., 0.),
+            across: (width, 0.),
+            along: (0., height),
+        };
+
+        let mut centres = Vec::new();
+        self.visit(whole, &mut centres);
+
+        let path = match self.config.curve_detail {
+            CurveDetail::Subdivide => {
+                let points = centres.iter().map(|&(x, y)| {
+                    let dark = self.darkness(&Cell {
+                        origin: (x - self.spacing / 2., y - self.spacing / 2.),
+                        across: (self.spacing, 0.),
+                        along: (0., self.spacing),
+                    });
+                    Vertex::with_intensity(x, y, dark)
+                });
+                Subpath::polyline(points)
+            }
+            CurveDetail::Sinusoid => {
+                let step = width / self.area.dimensions().0 as f32 / self.config.sample_freq;
+                let centre = resample(&centres, step);
+                wave_along(
+                    &centre,
+                    &self.area,
+                    self.scale,
+                    self.spacing,
+                    Modulation::Frequency,
+                    self.config,
+                )
+            }
+        };
+
+        tracker.line_done();
+        Some(path.into_iter().collect())
+    }
+
+    /// Add the centres of a cell's leaves to the path, in the curve's order.
+    fn visit(&self, cell: Cell, centres: &mut Vec<(f32, f32)>) {
+        if !self.split(&cell) {
+            centres.push(cell.at(0.5, 0.5));
+            return;
+        }
+
+        match self.config.curve {
+            Curve::Hilbert => {
+                // Across the first half, along, then back across the second
+                // half. The first and last children are turned a quarter
+                // (swapping their sides), so the curve leaves each one next
+                // to the following child.
+                let turned = |origin: (f32, f32), flip: f32| Cell {
+                    origin: cell.at(origin.0, origin.1),
+                    across: (cell.along.0 * flip / 2., cell.along.1 * flip / 2.),
+                    along: (cell.across.0 * flip / 2., cell.across.1 * flip / 2.),
+                };
+                self.visit(turned((0., 0.), 1.), centres);
+                self.visit(cell.child((0.5, 0.), 0.5, 0.5), centres);
+                self.visit(cell.child((0.5, 0.5), 0.5, 0.5), centres);
+                self.visit(turned((0.5, 1.), -1.), centres);
+            }
+            Curve::Peano => {
+                // Back and forth along columns of three. Every other child
+                // is mirrored, so each starts where the previous one ended.
+                for i in 0..3 {
+                    for step in 0..3 {
+                        let j = if i % 2 == 1 { 2 - step } else { step };
+                        let flip_across = j % 2 == 1;
+                        let flip_along = i % 2 == 1;
+                        let origin = (
+                            (i + usize::from(flip_across)) as f32 / 3.,
+                            (j + usize::from(flip_along)) as f32 / 3.,
+                        );
+                        let sign = |flip: bool| if flip { -1. / 3. } else { 1. / 3. };
+                        let child = cell.child(origin, sign(flip_across), sign(flip_along));
+                        self.visit(child, centres);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether a cell is split further.
+    ///
+    /// The spacing of the curve is the size of its cells. A cell is split
+    /// while it is larger than the spacing its darkness asks for: `spacing`
+    /// where black, wider in proportion where lighter, up to
+    /// `LIGHTEST_SPACING` rows. With `CurveDetail::Sinusoid` the curve is
+    /// split evenly to `spacing`.
+    fn split(&self, cell: &Cell) -> bool {
+        let target = match self.config.curve_detail {
+            CurveDetail::Subdivide => {
+                let dark = self.darkness(cell).max(1. / LIGHTEST_SPACING);
+                self.spacing / dark
+            }
+            CurveDetail::Sinusoid => self.spacing,
+        };
+
+        cell.size() > target
+    }
+
+    /// Average darkness of the image over a cell.
+    fn darkness(&self, cell: &Cell) -> f32 {
+        let (img_width, img_height) = self.area.dimensions();
+        let px_x = img_width as f32 / self.config.width as f32;
+        let px_y = img_height as f32 / self.config.height as f32;
+
+        let (a, b) = (cell.at(0., 0.), cell.at(1., 1.));
+        let value = self.area.average(
+            a.0.min(b.0) * px_x,
+            a.1.min(b.1) * px_y,
+            a.0.max(b.0) * px_x,
+            a.1.max(b.1) * px_y,
+        );
+        darkness(value)
+    }
+}
+
+/// Points every `step` along a polyline, for drawing a sinusoid along it.
+fn resample(points: &[(f32, f32)], step: f32) -> Vec<(f32, f32)> {
+    let mut resampled: Vec<(f32, f32)> = points.first().copied().into_iter().collect();
+
+    // Distance along the current segment to the next point
+    let mut next = step;
+    for pair in points.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let length = (b.0 - a.0).hypot(b.1 - a.1);
+
+        while next <= length {
+            let t = next / length;
+            resampled.push((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t));
+            next += step;
+        }
+        next -= length;
+    }
+
+    resampled
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use image::Luma;
+
+    use super::*;
+
+    /// Trace `luma` at one output unit per pixel, with lines a unit apart.
+    fn trace(luma: &GrayImage, curve: Curve) -> Vec<(f32, f32)> {
+        let config = SinusoidShadingConfig {
+            width: luma.width() as usize,
+            height: luma.height() as usize,
+            lines: luma.height() as usize,
+            curve,
+            ..Default::default()
+        };
+        let scale = FrequencyScale::new(&average_rows(luma, &config), &config);
+        let cancel = CancellationToken::new();
+        let tracker = Tracker::new(&|_, _| {}, &cancel, 1);
+
+        let paths = SpaceFilling::new(luma, &scale, &config)
+            .trace(&tracker)
+            .unwrap();
+        assert_eq!(paths.len(), 1);
+        paths[0].vertices().map(|v| v.position()).collect()
+    }
+
+    /// Check that a curve on black visits every unit cell of a `size`
+    /// square once, moving one cell at a time.
+    fn check_fills(curve: Curve, size: u32) {
+        let points = trace(&GrayImage::from_pixel(size, size, Luma([0])), curve);
+        assert_eq!(points.len(), (size * size) as usize);
+
+        let cells: HashSet<_> = points
+            .iter()
+            .map(|&(x, y)| (x.floor() as u32, y.floor() as u32))
+            .collect();
+        assert_eq!(cells.len(), points.len());
+        assert!(cells.iter().all(|&(x, y)| x < size && y < size));
+
+        for pair in points.windows(2) {
+            let step = (pair[1].0 - pair[0].0).hypot(pair[1].1 - pair[0].1);
+            assert!((step - 1.).abs() < 1e-4, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn hilbert_fills_black() {
+        check_fills(Curve::Hilbert, 16);
+    }
+
+    #[test]
+    fn peano_fills_black() {
+        check_fills(Curve::Peano, 27);
+    }
+
+    #[test]
+    fn darker_side_is_split_more() {
+        let luma = GrayImage::from_fn(32, 32, |x, _| Luma([if x < 16 { 0 } else { 200 }]));
+        let points = trace(&luma, Curve::Hilbert);
+
+        let dark = points.iter().filter(|&&(x, _)| x < 16.).count();
+        assert_eq!(dark, 16 * 32);
+        assert!(points.len() - dark < dark / 4);
+    }
+
+    #[test]
+    fn resample_keeps_steps_even() {
+        let points = resample(&[(0., 0.), (2.5, 0.), (2.5, 2.5)], 1.);
+        assert_eq!(
+            points,
+            vec![
+                (0., 0.),
+                (1., 0.),
+                (2., 0.),
+                (2.5, 0.5),
+                (2.5, 1.5),
+                (2.5, 2.5)
+            ]
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, Luma};
+
+use crate::sinusoid::gamma_table;
+use crate::SinusoidShadingConfig;
+
+/// Millimetres per inch
+const MM_PER_INCH: f32 = 25.4;
+
+/// 8 by 8 Bayer matrix for ordered dithering, thresholds from 0 to 63
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// How grey levels are turned into black and white pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Dither {
+    /// Floyd–Steinberg error diffusion
+    #[default]
+    FloydSteinberg,
+
+    /// Jarvis, Judice and Ninke error diffusion, spread over more pixels
+    /// for smoother tones
+    Jarvis,
+
+    /// Stucki error diffusion, a sharper variant of Jarvis
+    Stucki,
+
+    /// Atkinson error diffusion. Only part of the error is spread, which
+    /// keeps highlights and shadows clean at the cost of some contrast.
+    Atkinson,
+
+    /// Ordered dithering with an 8 by 8 Bayer matrix, a regular crosshatch
+    /// like pattern
+    Bayer,
+}
+
+value_enum_strings!(Dither);
+
+impl Dither {
+    /// Error diffusion kernel, as (dx, dy, weight) for the pixels after the
+    /// current one, and the sum the weights are divided by.
+    fn kernel(self) -> (&'static [(isize, usize, f32)], f32) {
+        match self {
+            Dither::FloydSteinberg => (&[(1, 0, 7.), (-1, 1, 3.), (0, 1, 5.), (1, 1, 1.)], 16.),
+            Dither::Jarvis => (
+                &[
+                    (1, 0, 7.),
+                    (2, 0, 5.),
+                    (-2, 1, 3.),
+                    (-1, 1, 5.),
+                    (0, 1, 7.),
+                    (1, 1, 5.),
+                    (2, 1, 3.),
+                    (-2, 2, 1.),
+                    (-1, 2, 3.),
+                    (0, 2, 5.),
+                    (1, 2, 3.),
+                    (2, 2, 1.),
+                ],
+                48.,
+            ),
+            Dither::Stucki => (
+                &[
+                    (1, 0, 8.),
+                    (2, 0, 4.),
+                    (-2, 1, 2.),
+                    (-1, 1, 4.),
+                    (0, 1, 8.),
+                    (1, 1, 4.),
+                    (2, 1, 2.),
+                    (-2, 2, 1.),
+                    (-1, 2, 2.),
+                    (0, 2, 4.),
+                    (1, 2, 2.),
+                    (2, 2, 1.),
+                ],
+                42.,
+            ),
+            Dither::Atkinson => (
+                &[
+                    (1, 0, 1.),
+                    (2, 0, 1.),
+                    (-1, 1, 1.),
+                    (0, 1, 1.),
+                    (1, 1, 1.),
+                    (0, 2, 1.),
+                ],
+                8.,
+            ),
+            Dither::Bayer => (&[], 1.),
+        }
+    }
+}
+
+/// File format of a dithered bitmap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RasterFormat {
+    /// 1-bit grayscale PNG
+    #[default]
+    Png,
+
+    /// 1-bit Windows bitmap, which some laser software reads more readily
+    Bmp,
+}
+
+value_enum_strings!(RasterFormat);
+
+/// Dither an image for a laser's raster (engraving) mode.
+///
+/// The image is resized to the physical size of the output (see
+/// `SinusoidShadingConfig::physical_size_mm`) at `dpi` pixels per inch,
+/// gamma corrected as for the shading methods, and dithered to black and
+/// white.
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration struct, for the size and gamma.
+/// * `dpi` - Resolution of the laser's raster mode.
+/// * `dither` - The dithering method.
+///
+/// # Returns
+/// * An image of only black (0) and white (255) pixels.
+pub fn dither_image(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    dpi: f32,
+    dither: Dither,
+) -> GrayImage {
+    let (width_mm, height_mm) = config.physical_size_mm();
+    let pixels = |mm: f32| ((mm / MM_PER_INCH * dpi).round() as u32).max(1);
+    let (width, height) = (pixels(width_mm), pixels(height_mm));
+
+    let resized = imageops::resize(&img.to_luma8(), width, height, FilterType::Triangle);
+    let gamma = gamma_table(config.gamma);
+    let mut values: Vec<f32> = resized
+        .pixels()
+        .map(|p| f32::from(gamma[p.0[0] as usize]))
+        .collect();
+
+    let (width, height) = (width as usize, height as usize);
+    if dither == Dither::Bayer {
+        for (i, value) in values.iter_mut().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let threshold = (f32::from(BAYER[y % 8][x % 8]) + 0.5) / 64. * 255.;
+            *value = if *value > threshold { 255. } else { 0. };
+        }
+    } else {
+        diffuse(&mut values, width, height, dither.kernel());
+    }
+
+    GrayImage::from_fn(width as u32, height as u32, |x, y| {
+        Luma([values[y as usize * width + x as usize] as u8])
+    })
+}
+
+/// Threshold every pixel in place, spreading the error to the pixels after
+/// it.
+///
+/// Rows are scanned in alternating directions (serpentine), with the kernel
+/// mirrored on the way back, which avoids the diagonal streaks of always
+/// scanning left to right.
+fn diffuse(
+    values: &mut [f32],
+    width: usize,
+    height: usize,
+    (kernel, sum): (&[(isize, usize, f32)], f32),
+) {
+    for y in 0..height {
+        let backwards = y % 2 == 1;
+        for step in 0..width {
+            let x = if backwards { width - 1 - step } else { step };
+            let i = y * width + x;
+
+            let old = values[i];
+            let new = if old > 127.5 { 255. } else { 0. };
+            values[i] = new;
+
+            let error = (old - new) / sum;
+            for &(dx, dy, weight) in kernel {
+                let dx = if backwards { -dx } else { dx };
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx < 0 || nx >= width as isize || ny >= height {
+                    continue;
+                }
+                values[ny * width + nx as usize] += error * weight;
+            }
+        }
+    }
+}
+
+/// Write a dithered image as a 1-bit bitmap, with its resolution recorded
+/// so that laser software imports it at the right size.
+///
+/// # Arguments
+/// * `img` - An image of black and white pixels, e.g. from `dither_image`.
+///   Any pixel that isn't black is written as white.
+/// * `dpi` - The resolution the image was dithered at.
+/// * `format` - The file format.
+/// * `w` - Where to write the file.
+pub fn write_bitmap(
+    img: &GrayImage,
+    dpi: f32,
+    format: RasterFormat,
+    w: &mut dyn Write,
+) -> io::Result<()> {
+    // Both formats store resolution in pixels per metre
+    let per_metre = (dpi / MM_PER_INCH * 1000.).round() as u32;
+
+    match format {
+        RasterFormat::Png => write_png(img, per_metre, w),
+        RasterFormat::Bmp => write_bmp(img, per_metre, w),
+    }
+}
+
+/// Pack rows of pixels 8 to a byte, most significant bit first, with white
+/// as 1. Each row starts on a new byte, and is padded to `align` bytes.
+fn pack_rows(img: &GrayImage, rows: impl Iterator<Item = u32>, align: usize) -> Vec<u8> {
+    let row_bytes = (img.width() as usize).div_ceil(8).div_ceil(align) * align;
+    let mut packed = Vec::new();
+    for y in rows {
+        let mut row = vec![0u8; row_bytes];
+        for x in 0..img.width() {
+            if img.get_pixel(x, y).0[0] != 0 {
+                row[x as usize / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        packed.extend(row);
+    }
+    packed
+}
+
+/// Write a 1-bit grayscale PNG with a pHYs chunk for the resolution.
+fn write_png(img: &GrayImage, per_metre: u32, w: &mut dyn Write) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(w, img.width(), img.height());
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    encoder.set_pixel_dims(Some(png::PixelDimensions {
+        xppu: per_metre,
+        yppu: per_metre,
+        unit: png::Unit::Meter,
+    }));
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pack_rows(img, 0..img.height(), 1))?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Write a 1-bit BMP with a black and white palette.
+///
+/// The image crate only writes 8-bit and deeper bitmaps, so the headers are
+/// written by hand. Rows are stored bottom up and padded to 4 bytes.
+fn write_bmp(img: &GrayImage, per_metre: u32, w: &mut dyn Write) -> io::Result<()> {
+    let pixels = pack_rows(img, (0..img.height()).rev(), 4);
+
+    // File header (14 bytes), info header (40 bytes) and two palette entries
+    let offset = 14 + 40 + 2 * 4;
+    let size = offset + pixels.len() as u32;
+
+    let mut header = Vec::with_capacity(offset as usize);
+    header.extend(b"BM");
+    header.extend(size.to_le_bytes());
+    header.extend([0; 4]); // Reserved
+    header.extend(offset.to_le_bytes());
+
+    header.extend(40u32.to_le_bytes());
+    header.extend((img.width() as i32).to_le_bytes());
+    header.extend((img.height() as i32).to_le_bytes());
+    header.extend(1u16.to_le_bytes()); // Colour planes
+    header.extend(1u16.to_le_bytes()); // Bits per pixel
+    header.extend(0u32.to_le_bytes()); // No compression
+    header.extend((pixels.len() as u32).to_le_bytes());
+    header.extend(per_metre.to_le_bytes());
+    header.extend(per_metre.to_le_bytes());
+    header.extend(2u32.to_le_bytes()); // Colours in the palette
+    header.extend(0u32.to_le_bytes()); // All colours are important
+
+    // Palette as blue, green, red and a reserved byte: 0 is black, 1 white
+    header.extend([0, 0, 0, 0, 255, 255, 255, 0]);
+
+    w.write_all(&header)?;
+    w.write_all(&pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2 by 1 inch output, 128 by 64 pixels at 64 DPI.
+    fn config() -> SinusoidShadingConfig {
+        SinusoidShadingConfig {
+            width: 2,
+            height: 1,
+            units: crate::Units::In,
+            ..Default::default()
+        }
+    }
+
+    fn mean(img: &GrayImage) -> f32 {
+        img.pixels().map(|p| f32::from(p.0[0])).sum::<f32>() / img.len() as f32
+    }
+
+    /// A 4 by 3 pattern of black and white.
+    fn pattern() -> GrayImage {
+        GrayImage::from_fn(4, 3, |x, y| Luma([if (x + y) % 3 == 0 { 0 } else { 255 }]))
+    }
+
+    #[test]
+    fn sized_by_dpi() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(10, 5, Luma([128])));
+        let dithered = dither_image(&img, &config(), 64., Dither::Bayer);
+        assert_eq!(dithered.dimensions(), (128, 64));
+    }
+
+    #[test]
+    fn keeps_the_mean_tone() {
+        for grey in [32, 128, 200] {
+            let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(128, 64, Luma([grey])));
+            for dither in Dither::value_variants() {
+                let dithered = dither_image(&img, &config(), 64., *dither);
+                assert!(dithered.pixels().all(|p| p.0[0] == 0 || p.0[0] == 255));
+
+                // Atkinson drops a quarter of the error, which clips
+                // shadows and highlights, so it only keeps the mid tones
+                if *dither == Dither::Atkinson && grey != 128 {
+                    continue;
+                }
+                let mean = mean(&dithered);
+                assert!(
+                    (mean - f32::from(grey)).abs() < 4.,
+                    "{} {}: {}",
+                    dither,
+                    grey,
+                    mean
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bmp_headers_and_rows() {
+        let mut bmp = Vec::new();
+        write_bitmap(&pattern(), 254., RasterFormat::Bmp, &mut bmp).unwrap();
+
+        let u32_at = |i: usize| u32::from_le_bytes(bmp[i..i + 4].try_into().unwrap());
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(u32_at(2) as usize, bmp.len());
+        assert_eq!(u32_at(10), 62);
+        assert_eq!((u32_at(18), u32_at(22)), (4, 3));
+        assert_eq!(u16::from_le_bytes([bmp[28], bmp[29]]), 1);
+        assert_eq!(u32_at(38), 10_000);
+
+        // Bottom row first, each padded to 4 bytes, white as 1
+        assert_eq!(
+            &bmp[62..],
+            &[
+                0b1011_0000,
+                0,
+                0,
+                0,
+                0b1101_0000,
+                0,
+                0,
+                0,
+                0b0110_0000,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn png_reads_back() {
+        let mut png = Vec::new();
+        write_bitmap(&pattern(), 254., RasterFormat::Png, &mut png).unwrap();
+
+        let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let dims = reader.info().pixel_dims.unwrap();
+        assert_eq!((dims.xppu, dims.yppu), (10_000, 10_000));
+        assert_eq!(reader.info().bit_depth, png::BitDepth::One);
+
+        let decoded = image::load_from_memory(&png).unwrap().to_luma8();
+        assert_eq!(decoded, pattern());
+    }
+}
@@ -0,0 +1,511 @@
+use clap::ValueEnum;
+
+use image::{DynamicImage, GrayImage};
+
+use crate::area::AreaAverages;
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::parallel;
+use crate::sinusoid::{average_rows, darkness, FrequencyScale, Tracker};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Streamlines stop when they come closer than this fraction of the spacing
+/// to another one
+const TEST_RATIO: f32 = 0.5;
+
+/// Seeds closer than this fraction of the spacing to a streamline are
+/// skipped. It is a little under 1, so that seeds placed exactly a spacing
+/// beside a streamline aren't lost to rounding.
+const SEED_RATIO: f32 = 0.9;
+
+/// Structure tensors weaker than this are treated as flat, where
+/// streamlines run horizontally like the rows of the sinusoid method
+const FLAT: f32 = 1e-3;
+
+/// How the darkness under a streamline changes the sinusoid drawn along it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Modulation {
+    /// Darker tones give higher frequencies, like the sinusoid method
+    #[default]
+    Frequency,
+
+    /// Darker tones give larger waves at `max_freq`
+    Amplitude,
+}
+
+value_enum_strings!(Modulation);
+
+/// Draw sinusoids along streamlines of a grayscale image's edge tangents.
+///
+/// # Returns
+/// * A single layer of streamlines, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_flow(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let luma = img.to_luma8();
+    let scale = FrequencyScale::new(&average_rows(&luma, config), config);
+    let flow = Flow::new(&luma, &scale, config);
+    let tracker = Tracker::new(progress, cancel, flow.seed_rows());
+
+    let lines = flow.trace(&tracker).ok_or(ImageProcessError::Cancelled)?;
+    Ok(vec![Layer::from_subpaths("flow", lines)])
+}
+
+/// Traces evenly spaced streamlines through the edge tangent field of an
+/// image, and draws a modulated sinusoid along each.
+///
+/// Streamlines are placed with Jobard and Lefer's method: each one is
+/// traced from a seed until it nears another, and new seeds are tried one
+/// spacing to either side of every streamline, so neighbours end up about
+/// a spacing apart. The spacing is the same as between the rows of the
+/// sinusoid method.
+pub(crate) struct Flow<'a> {
+    config: &'a SinusoidShadingConfig,
+
+    /// The frequency scale, shared with the other methods so the same tone
+    /// gives the same frequency
+    scale: &'a FrequencyScale,
+
+    /// The image to sample
+    area: AreaAverages,
+
+    /// Direction of the streamlines
+    field: TangentField,
+
+    /// Distance between neighbouring streamlines
+    spacing: f32,
+
+    /// Length of each step along a streamline, the same as between the
+    /// samples of the horizontal sinusoids
+    step: f32,
+}
+
+impl<'a> Flow<'a> {
+    /// Prepare an image for tracing.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `scale` - The frequency scale.
+    /// * `config` - The configuration struct.
+    pub(crate) fn new(
+        luma: &GrayImage,
+        scale: &'a FrequencyScale,
+        config: &'a SinusoidShadingConfig,
+    ) -> Self {
+        let spacing = config.height as f32 / config.line_count() as f32;
+
+        // Smooth the field over about a row, so streamlines follow the
+        // larger shapes instead of every bit of texture
+        let radius = (luma.height() as f32 / config.line_count() as f32).max(1.) as usize;
+
+        Flow {
+            config,
+            scale,
+            area: AreaAverages::new(luma, config.gamma),
+            field: TangentField::new(luma, radius, config),
+            spacing,
+            step: config.width as f32 / luma.width() as f32 / config.sample_freq,
+        }
+    }
+
+    /// Number of rows of seeds, for progress reporting.
+    pub(crate) fn seed_rows(&self) -> usize {
+        (self.config.height as f32 / self.spacing).ceil() as usize
+    }
+
+    /// Trace the streamlines and draw their sinusoids.
+    ///
+    /// # Returns
+    /// * The sinusoids, or `None` if it was cancelled.
+    pub(crate) fn trace(&self, tracker: &Tracker) -> Option<Vec<Subpath>> {
+        let centres = self.streamlines(tracker)?;
+
+        let config = self.config;
+        let lines = parallel::map_range(centres.len(), |i| {
+            wave_along(
+                &centres[i],
+                &self.area,
+                self.scale,
+                self.spacing,
+                config.flow_modulation,
+                config,
+            )
+        });
+        Some(lines.into_iter().flatten().collect())
+    }
+
+    /// Place the centre lines of the streamlines.
+    ///
+    /// Seeds are tried on a grid, row by row. After each streamline, the
+    /// seeds beside it are tried before moving on along the grid.
+    fn streamlines(&self, tracker: &Tracker) -> Option<Vec<Vec<(f32, f32)>>> {
+        let (width, height) = (self.config.width as f32, self.config.height as f32);
+        let mut occupied = Occupied::new(width, height, self.spacing);
+        let mut lines: Vec<Vec<(f32, f32)>> = Vec::new();
+
+        for row in 0..self.seed_rows() {
+            if tracker.cancelled() {
+                return None;
+            }
+
+            let y = (row as f32 + 0.5) * self.spacing;
+            let cols = (width / self.spacing).ceil() as usize;
+            for col in 0..cols {
+                let mut seeds = vec![((col as f32 + 0.5) * self.spacing, y)];
+                while let Some(seed) = seeds.pop() {
+                    if !self.inside(seed) || occupied.near(seed, self.spacing * SEED_RATIO, None) {
+                        continue;
+                    }
+
+                    let line = self.streamline(seed, lines.len(), &mut occupied);
+                    seeds.extend(self.side_seeds(&line));
+                    lines.push(line);
+                }
+            }
+
+            tracker.line_done();
+        }
+
+        Some(lines)
+    }
+
+    /// Trace one streamline both ways from a seed, until it leaves the
+    /// output or nears another streamline (or itself).
+    fn streamline(&self, seed: (f32, f32), id: usize, occupied: &mut Occupied) -> Vec<(f32, f32)> {
+        let test = self.spacing * TEST_RATIO;
+
+        // A streamline may curl back on itself, but mustn't stop on the
+        // points just behind it
+        let lag = (2. * test / self.step).ceil() as isize + 1;
+
+        occupied.insert(seed, id, 0);
+        let direction = self.field.at(seed);
+        let mut halves = [Vec::new(), Vec::new()];
+        for (half, sign) in halves.iter_mut().zip([1., -1.]) {
+            let mut point = seed;
+            let mut heading = (direction.0 * sign, direction.1 * sign);
+            let max_steps =
+                (4. * (self.config.width + self.config.height) as f32 / self.step) as usize;
+
+            for n in 1..=max_steps as isize {
+                // Midpoint method, keeping the direction consistent since
+                // tangents have no sign
+                let first = align(self.field.at(point), heading);
+                let mid = (
+                    point.0 + first.0 * self.step / 2.,
+                    point.1 + first.1 * self.step / 2.,
+                );
+                heading = align(self.field.at(mid), first);
+                let next = (
+                    point.0 + heading.0 * self.step,
+                    point.1 + heading.1 * self.step,
+                );
+
+                let index = n * sign as isize;
+                if !self.inside(next) || occupied.near(next, test, Some((id, index, lag))) {
+                    break;
+                }
+
+                occupied.insert(next, id, index);
+                half.push(next);
+                point = next;
+            }
+        }
+
+        let [forward, backward] = halves;
+        backward
+            .into_iter()
+            .rev()
+            .chain(std::iter::once(seed))
+            .chain(forward)
+            .collect()
+    }
+
+    /// Seeds one spacing to either side of a streamline, at every spacing
+    /// along it.
+    fn side_seeds(&self, line: &[(f32, f32)]) -> Vec<(f32, f32)> {
+        let every = ((self.spacing / self.step) as usize).max(1);
+        line.windows(2)
+            .step_by(every)
+            .flat_map(|pair| {
+                let normal = normal(pair[0], pair[1]);
+                [1., -1.].map(|side| {
+                    (
+                        pair[0].0 + normal.0 * self.spacing * side,
+                        pair[0].1 + normal.1 * self.spacing * side,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn inside(&self, (x, y): (f32, f32)) -> bool {
+        (0. ..=self.config.width as f32).contains(&x)
+            && (0. ..=self.config.height as f32).contains(&y)
+    }
+}
+
+/// Draw a modulated sinusoid along a centre line, as
+/// `Crosshatch::trace_line` does along a straight one.
+///
+/// # Arguments
+/// * `centre` - Points along the centre line, `1 / config.sample_freq`
+///   pixels apart.
+/// * `area` - The image, averaged over a square of `spacing` around each
+///   point.
+/// * `scale` - The frequency scale.
+/// * `spacing` - Distance to the neighbouring lines. The amplitude is a
+///   ratio of it, as of the row height for the horizontal sinusoids.
+/// * `modulation` - Whether the darkness sets the frequency or amplitude.
+/// * `config` - The configuration struct.
+///
+/// # Returns
+/// * The sinusoid, or `None` if the centre line is too short.
+pub(crate) fn wave_along(
+    centre: &[(f32, f32)],
+    area: &AreaAverages,
+    scale: &FrequencyScale,
+    spacing: f32,
+    modulation: Modulation,
+    config: &SinusoidShadingConfig,
+) -> Option<Subpath> {
+    if centre.len() < 2 {
+        return None;
+    }
+
+    let fs = config.sample_freq;
+    let amp = config.amplitude * spacing;
+
+    // Image pixels per output unit, and the size of the averaged square
+    let (img_width, img_height) = area.dimensions();
+    let px_x = img_width as f32 / config.width as f32;
+    let px_y = img_height as f32 / config.height as f32;
+    let half = spacing / 2.;
+
+    let mut phase = 0.;
+    let points = centre.iter().enumerate().map(|(i, &(x, y))| {
+        let value = area.average(
+            (x - half) * px_x,
+            (y - half) * px_y,
+            (x + half) * px_x,
+            (y + half) * px_y,
+        );
+        let dark = darkness(value);
+
+        let wave = match modulation {
+            Modulation::Frequency => {
+                phase += scale.frequency(value);
+                amp * (phase / fs).sin()
+            }
+            Modulation::Amplitude => {
+                phase += config.max_freq;
+                amp * dark * (phase / fs).sin()
+            }
+        };
+
+        let (a, b) = (
+            centre[i.saturating_sub(1)],
+            centre[(i + 1).min(centre.len() - 1)],
+        );
+        let normal = normal(a, b);
+        Vertex::with_intensity(x + normal.0 * wave, y + normal.1 * wave, dark)
+    });
+
+    Subpath::polyline(points)
+}
+
+/// Unit normal of the segment from `a` to `b`.
+fn normal(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx.hypot(dy).max(f32::EPSILON);
+    (-dy / length, dx / length)
+}
+
+/// `tangent`, flipped if needed to point the same way as `heading`.
+fn align(tangent: (f32, f32), heading: (f32, f32)) -> (f32, f32) {
+    if tangent.0 * heading.0 + tangent.1 * heading.1 < 0. {
+        (-tangent.0, -tangent.1)
+    } else {
+        tangent
+    }
+}
+
+/// Direction along the edges of an image at each pixel.
+///
+/// The direction comes from the smoothed structure tensor of the Sobel
+/// gradients: across the strongest gradient, so along edges and the
+/// contours of shading. Averaging the tensor rather than the gradients
+/// keeps opposite gradients on either side of a line from cancelling out.
+struct TangentField {
+    /// Image width and height in pixels
+    width: usize,
+    height: usize,
+
+    /// Image pixels per output unit
+    px_x: f32,
+    px_y: f32,
+
+    /// Unit tangent of each pixel, row by row
+    tangents: Vec<(f32, f32)>,
+}
+
+impl TangentField {
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `radius` - Radius of the box blur applied to the tensor, in pixels.
+    /// * `config` - The configuration struct, for the output size.
+    fn new(luma: &GrayImage, radius: usize, config: &SinusoidShadingConfig) -> Self {
+        let (width, height) = (luma.width() as usize, luma.height() as usize);
+        let pixel = |x: usize, y: usize| f32::from(luma.as_raw()[y * width + x]) / 255.;
+
+        // Structure tensor (gx², gx gy, gy²) of each pixel, with the edges of
+        // the image repeated
+        let mut xx = vec![0.; width * height];
+        let mut xy = vec![0.; width * height];
+        let mut yy = vec![0.; width * height];
+        for y in 0..height {
+            let (up, down) = (y.saturating_sub(1), (y + 1).min(height - 1));
+            for x in 0..width {
+                let (left, right) = (x.saturating_sub(1), (x + 1).min(width - 1));
+                let gx = pixel(right, up) + 2. * pixel(right, y) + pixel(right, down)
+                    - pixel(left, up)
+                    - 2. * pixel(left, y)
+                    - pixel(left, down);
+                let gy = pixel(left, down) + 2. * pixel(x, down) + pixel(right, down)
+                    - pixel(left, up)
+                    - 2. * pixel(x, up)
+                    - pixel(right, up);
+
+                let i = y * width + x;
+                (xx[i], xy[i], yy[i]) = (gx * gx, gx * gy, gy * gy);
+            }
+        }
+
+        for values in [&mut xx, &mut xy, &mut yy] {
+            box_blur(values, width, height, radius);
+        }
+
+        let tangents = (0..width * height)
+            .map(|i| {
+                if xx[i] + yy[i] < FLAT {
+                    return (1., 0.);
+                }
+
+                // Angle of the strongest gradient, and the tangent across it
+                let angle = 0.5 * (2. * xy[i]).atan2(xx[i] - yy[i]);
+                let (sin, cos) = angle.sin_cos();
+                (-sin, cos)
+            })
+            .collect();
+
+        TangentField {
+            width,
+            height,
+            px_x: width as f32 / config.width as f32,
+            px_y: height as f32 / config.height as f32,
+            tangents,
+        }
+    }
+
+    /// Tangent at a point in output units.
+    fn at(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let col = ((x * self.px_x).max(0.) as usize).min(self.width - 1);
+        let row = ((y * self.px_y).max(0.) as usize).min(self.height - 1);
+        self.tangents[row * self.width + col]
+    }
+}
+
+/// Blur values in place with a box of `2 * radius + 1` pixels, first along
+/// rows and then down columns.
+fn box_blur(values: &mut [f32], width: usize, height: usize, radius: usize) {
+    let mut line = Vec::new();
+    let mut blur = |values: &mut [f32], count: usize, stride: usize, start: usize| {
+        line.clear();
+        line.extend((0..count).map(|i| values[start + i * stride]));
+
+        // Running sum over the window, clamped to the line
+        let mut sum: f32 = line[..radius.min(count - 1) + 1].iter().sum();
+        for i in 0..count {
+            let (lo, hi) = (i.saturating_sub(radius), (i + radius).min(count - 1));
+            values[start + i * stride] = sum / (hi - lo + 1) as f32;
+
+            if i + radius + 1 < count {
+                sum += line[i + radius + 1];
+            }
+            if i >= radius {
+                sum -= line[i - radius];
+            }
+        }
+    };
+
+    for y in 0..height {
+        blur(values, width, 1, y * width);
+    }
+    for x in 0..width {
+        blur(values, height, width, x);
+    }
+}
+
+/// A point of a streamline, with the streamline and the index along it
+/// (negative before the seed)
+type Placed = ((f32, f32), usize, isize);
+
+/// Points of the streamlines placed so far, bucketed into square cells.
+struct Occupied {
+    /// Size of a cell, at least the largest distance searched
+    cell: f32,
+
+    /// Number of cells across and down
+    cols: usize,
+    rows: usize,
+
+    /// The points in each cell, row by row
+    cells: Vec<Vec<Placed>>,
+}
+
+impl Occupied {
+    fn new(width: f32, height: f32, cell: f32) -> Self {
+        let cols = (width / cell) as usize + 1;
+        let rows = (height / cell) as usize + 1;
+        Occupied {
+            cell,
+            cols,
+            rows,
+            cells: vec![Vec::new(); cols * rows],
+        }
+    }
+
+    fn cell_of(&self, (x, y): (f32, f32)) -> (usize, usize) {
+        let col = ((x / self.cell).max(0.) as usize).min(self.cols - 1);
+        let row = ((y / self.cell).max(0.) as usize).min(self.rows - 1);
+        (col, row)
+    }
+
+    fn insert(&mut self, point: (f32, f32), line: usize, index: isize) {
+        let (col, row) = self.cell_of(point);
+        self.cells[row * self.cols + col].push((point, line, index));
+    }
+
+    /// Whether any point is closer than `distance` (at most a cell).
+    ///
+    /// # Arguments
+    /// * `own` - The streamline being traced, the index along it and the
+    ///   lag. Its own points less than `lag` steps away are ignored.
+    fn near(&self, point: (f32, f32), distance: f32, own: Option<(usize, isize, isize)>) -> bool {
+        let (col, row) = self.cell_of(point);
+        let rows = row.saturating_sub(1)..=(row + 1).min(self.rows - 1);
+        let cols = col.saturating_sub(1)..=(col + 1).min(self.cols - 1);
+
+        rows.flat_map(|r| cols.clone().map(move |c| (r, c)))
+            .flat_map(|(r, c)| &self.cells[r * self.cols + c])
+            .any(|&(other, line, index)| {
+                let own_neighbour =
+                    own.is_some_and(|(id, at, lag)| id == line && (index - at).abs() < lag);
+                !own_neighbour
+                    && (other.0 - point.0).powi(2) + (other.1 - point.1).powi(2)
+                        < distance * distance
+            })
+    }
+}
@@ -0,0 +1,289 @@
+// A tiny single-stroke font for labels.
+//
+// Lasers and plotters follow paths, so labels made of `<text>` elements would
+// need converting before they could be engraved. These glyphs are plain
+// polylines instead, and can be drawn into SVGs and bitmaps alike.
+//
+// Glyphs are drawn on a grid 4 units wide and 6 units tall, with y pointing
+// down. Lowercase letters are drawn as uppercase.
+
+/// Height of a glyph in grid units
+const GLYPH_HEIGHT: f32 = 6.;
+
+/// Horizontal distance between the start of two glyphs, in grid units
+const GLYPH_ADVANCE: f32 = 6.;
+
+type Stroke = &'static [(f32, f32)];
+
+/// Strokes for a single character. Unknown characters are blank.
+fn glyph(c: char) -> &'static [Stroke] {
+    match c.to_ascii_uppercase() {
+        'A' => &[
+            &[(0., 6.), (0., 2.), (2., 0.), (4., 2.), (4., 6.)],
+            &[(0., 4.), (4., 4.)],
+        ],
+        'B' => &[
+            &[
+                (0., 0.),
+                (0., 6.),
+                (3., 6.),
+                (4., 5.),
+                (4., 4.),
+                (3., 3.),
+                (0., 3.),
+            ],
+            &[(0., 0.), (3., 0.), (4., 1.), (4., 2.), (3., 3.)],
+        ],
+        'C' => &[&[
+            (4., 1.),
+            (3., 0.),
+            (1., 0.),
+            (0., 1.),
+            (0., 5.),
+            (1., 6.),
+            (3., 6.),
+            (4., 5.),
+        ]],
+        'D' => &[&[
+            (0., 0.),
+            (0., 6.),
+            (2., 6.),
+            (4., 4.),
+            (4., 2.),
+            (2., 0.),
+            (0., 0.),
+        ]],
+        'E' => &[
+            &[(4., 0.), (0., 0.), (0., 6.), (4., 6.)],
+            &[(0., 3.), (3., 3.)],
+        ],
+        'F' => &[&[(4., 0.), (0., 0.), (0., 6.)], &[(0., 3.), (3., 3.)]],
+        'G' => &[&[
+            (4., 1.),
+            (3., 0.),
+            (1., 0.),
+            (0., 1.),
+            (0., 5.),
+            (1., 6.),
+            (3., 6.),
+            (4., 5.),
+            (4., 3.),
+            (2., 3.),
+        ]],
+        'H' => &[
+            &[(0., 0.), (0., 6.)],
+            &[(4., 0.), (4., 6.)],
+            &[(0., 3.), (4., 3.)],
+        ],
+        'I' => &[
+            &[(1., 0.), (3., 0.)],
+            &[(2., 0.), (2., 6.)],
+            &[(1., 6.), (3., 6.)],
+        ],
+        'J' => &[&[(4., 0.), (4., 5.), (3., 6.), (1., 6.), (0., 5.)]],
+        'K' => &[
+            &[(0., 0.), (0., 6.)],
+            &[(4., 0.), (0., 4.)],
+            &[(1., 3.), (4., 6.)],
+        ],
+        'L' => &[&[(0., 0.), (0., 6.), (4., 6.)]],
+        'M' => &[&[(0., 6.), (0., 0.), (2., 3.), (4., 0.), (4., 6.)]],
+        'N' => &[&[(0., 6.), (0., 0.), (4., 6.), (4., 0.)]],
+        'O' => &[OVAL],
+        'P' => &[&[
+            (0., 6.),
+            (0., 0.),
+            (3., 0.),
+            (4., 1.),
+            (4., 2.),
+            (3., 3.),
+            (0., 3.),
+        ]],
+        'Q' => &[OVAL, &[(2., 4.), (4., 6.)]],
+        'R' => &[
+            &[
+                (0., 6.),
+                (0., 0.),
+                (3., 0.),
+                (4., 1.),
+                (4., 2.),
+                (3., 3.),
+                (0., 3.),
+            ],
+            &[(2., 3.), (4., 6.)],
+        ],
+        'S' => &[&[
+            (4., 1.),
+            (3., 0.),
+            (1., 0.),
+            (0., 1.),
+            (0., 2.),
+            (1., 3.),
+            (3., 3.),
+            (4., 4.),
+            (4., 5.),
+            (3., 6.),
+            (1., 6.),
+            (0., 5.),
+        ]],
+        'T' => &[&[(0., 0.), (4., 0.)], &[(2., 0.), (2., 6.)]],
+        'U' => &[&[(0., 0.), (0., 5.), (1., 6.), (3., 6.), (4., 5.), (4., 0.)]],
+        'V' => &[&[(0., 0.), (2., 6.), (4., 0.)]],
+        'W' => &[&[(0., 0.), (1., 6.), (2., 3.), (3., 6.), (4., 0.)]],
+        'X' => &[&[(0., 0.), (4., 6.)], &[(4., 0.), (0., 6.)]],
+        'Y' => &[&[(0., 0.), (2., 3.), (4., 0.)], &[(2., 3.), (2., 6.)]],
+        'Z' => &[&[(0., 0.), (4., 0.), (0., 6.), (4., 6.)]],
+        '0' => &[OVAL, &[(0., 5.), (4., 1.)]],
+        '1' => &[&[(1., 1.), (2., 0.), (2., 6.)], &[(1., 6.), (3., 6.)]],
+        '2' => &[&[
+            (0., 1.),
+            (1., 0.),
+            (3., 0.),
+            (4., 1.),
+            (4., 2.),
+            (0., 6.),
+            (4., 6.),
+        ]],
+        '3' => &[
+            &[
+                (0., 1.),
+                (1., 0.),
+                (3., 0.),
+                (4., 1.),
+                (4., 2.),
+                (3., 3.),
+                (4., 4.),
+                (4., 5.),
+                (3., 6.),
+                (1., 6.),
+                (0., 5.),
+            ],
+            &[(1., 3.), (3., 3.)],
+        ],
+        '4' => &[&[(3., 6.), (3., 0.), (0., 4.), (4., 4.)]],
+        '5' => &[&[
+            (4., 0.),
+            (0., 0.),
+            (0., 3.),
+            (3., 3.),
+            (4., 4.),
+            (4., 5.),
+            (3., 6.),
+            (1., 6.),
+            (0., 5.),
+        ]],
+        '6' => &[&[
+            (3., 0.),
+            (1., 0.),
+            (0., 1.),
+            (0., 5.),
+            (1., 6.),
+            (3., 6.),
+            (4., 5.),
+            (4., 4.),
+            (3., 3.),
+            (0., 3.),
+        ]],
+        '7' => &[&[(0., 0.), (4., 0.), (1., 6.)]],
+        '8' => &[&[
+            (1., 3.),
+            (0., 2.),
+            (0., 1.),
+            (1., 0.),
+            (3., 0.),
+            (4., 1.),
+            (4., 2.),
+            (3., 3.),
+            (1., 3.),
+            (0., 4.),
+            (0., 5.),
+            (1., 6.),
+            (3., 6.),
+            (4., 5.),
+            (4., 4.),
+            (3., 3.),
+        ]],
+        '9' => &[&[
+            (4., 3.),
+            (1., 3.),
+            (0., 2.),
+            (0., 1.),
+            (1., 0.),
+            (3., 0.),
+            (4., 1.),
+            (4., 5.),
+            (3., 6.),
+            (1., 6.),
+        ]],
+        '.' => &[&[(1.75, 6.), (2.25, 6.)]],
+        ',' => &[&[(2., 5.5), (1.5, 7.)]],
+        ':' => &[&[(2., 1.5), (2., 2.)], &[(2., 4.5), (2., 5.)]],
+        '=' => &[&[(0., 2.), (4., 2.)], &[(0., 4.), (4., 4.)]],
+        '-' => &[&[(0.5, 3.), (3.5, 3.)]],
+        '+' => &[&[(0., 3.), (4., 3.)], &[(2., 1.), (2., 5.)]],
+        '_' => &[&[(0., 6.), (4., 6.)]],
+        '/' => &[&[(0., 6.), (4., 0.)]],
+        '(' => &[&[(3., 0.), (2., 1.), (2., 5.), (3., 6.)]],
+        ')' => &[&[(1., 0.), (2., 1.), (2., 5.), (1., 6.)]],
+        '%' => &[
+            &[(0., 6.), (4., 0.)],
+            &[(0., 0.), (1., 0.), (1., 1.), (0., 1.), (0., 0.)],
+            &[(3., 5.), (4., 5.), (4., 6.), (3., 6.), (3., 5.)],
+        ],
+        _ => &[],
+    }
+}
+
+/// Shared by 'O', 'Q' and '0'
+const OVAL: Stroke = &[
+    (1., 0.),
+    (3., 0.),
+    (4., 1.),
+    (4., 5.),
+    (3., 6.),
+    (1., 6.),
+    (0., 5.),
+    (0., 1.),
+    (1., 0.),
+];
+
+/// Lay out `text` as polylines.
+///
+/// # Arguments
+/// * `text` - The text to draw, on a single line.
+/// * `x` - Left edge of the text.
+/// * `y` - Top edge of the text.
+/// * `size` - Height of the text.
+///
+/// # Returns
+/// * One list of (x, y) points per stroke.
+pub(crate) fn text_paths(text: &str, x: f32, y: f32, size: f32) -> Vec<Vec<(f32, f32)>> {
+    let scale = size / GLYPH_HEIGHT;
+
+    text.chars()
+        .enumerate()
+        .flat_map(|(i, c)| {
+            let left = x + i as f32 * GLYPH_ADVANCE * scale;
+            glyph(c).iter().map(move |stroke| {
+                stroke
+                    .iter()
+                    .map(|&(gx, gy)| (left + gx * scale, y + gy * scale))
+                    .collect()
+            })
+        })
+        .collect()
+}
+
+/// Width of `text` when drawn with `text_paths` at the given size.
+pub(crate) fn text_width(text: &str, size: f32) -> f32 {
+    let chars = text.chars().count() as f32;
+
+    // The last glyph doesn't need the spacing after it
+    let grid_width = (chars * GLYPH_ADVANCE - (GLYPH_ADVANCE - 4.)).max(0.);
+    grid_width * size / GLYPH_HEIGHT
+}
+
+/// A sensible stroke width for text of the given size.
+pub(crate) fn stroke_width(size: f32) -> f32 {
+    size / 10.
+}
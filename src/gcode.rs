@@ -0,0 +1,262 @@
+use std::fmt::Write;
+
+use image::DynamicImage;
+
+use crate::geometry::{Geometry, Layer};
+use crate::machine::{MachineProfile, Origin};
+use crate::method::{shade, shade_with_progress};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Convert an image into G-code using the frequency modulated sinusoidal
+/// shading method.
+///
+/// The output is placed in the machine's origin corner, at the physical size
+/// of `config` (see `SinusoidShadingConfig::physical_size_mm`).
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration struct.
+/// * `machine` - Power, speeds and origin of the machine.
+///
+/// # Returns
+/// * The G-code program.
+pub fn process_image_gcode(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+) -> String {
+    geometry_gcode(&shade(img, config), config, machine)
+}
+
+/// Same as `process_image_gcode`, but reports progress and can be cancelled.
+/// See `process_image_with_progress`.
+pub fn process_image_gcode_with_progress(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    progress: impl Fn(usize, usize) + Sync,
+    cancel: &CancellationToken,
+) -> Result<String, ImageProcessError> {
+    let geometry = shade_with_progress(img, config, progress, cancel)?;
+    Ok(geometry_gcode(&geometry, config, machine))
+}
+
+/// Convert an image into one G-code program per layer, e.g. one per pen of a
+/// colour separation (see `SinusoidShadingConfig::separation`).
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration struct.
+/// * `machine` - Power, speeds and origin of the machine.
+///
+/// # Returns
+/// * The name of each layer (e.g. `cyan`) and its G-code program.
+pub fn process_image_gcode_layers(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+) -> Vec<(String, String)> {
+    geometry_gcode_layers(&shade(img, config), config, machine)
+}
+
+/// Same as `process_image_gcode_layers`, but reports progress and can be
+/// cancelled. See `process_image_with_progress`.
+pub fn process_image_gcode_layers_with_progress(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+    progress: impl Fn(usize, usize) + Sync,
+    cancel: &CancellationToken,
+) -> Result<Vec<(String, String)>, ImageProcessError> {
+    let geometry = shade_with_progress(img, config, progress, cancel)?;
+    Ok(geometry_gcode_layers(&geometry, config, machine))
+}
+
+/// Write each layer of the geometry of a shading method as its own G-code
+/// program, see `geometry_gcode`.
+///
+/// # Returns
+/// * The name of each layer, and its G-code program.
+pub fn geometry_gcode_layers(
+    geometry: &Geometry,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+) -> Vec<(String, String)> {
+    geometry
+        .layers
+        .iter()
+        .map(|layer| {
+            let gcode = lines_gcode(std::slice::from_ref(layer), config, machine);
+            (layer.name.clone(), gcode)
+        })
+        .collect()
+}
+
+/// Write the geometry of a shading method as a G-code program.
+///
+/// # Arguments
+/// * `geometry` - The geometry, shaded for a `Canvas` of `config.width` by
+///   `config.height`.
+/// * `config` - The configuration struct, for the physical size.
+/// * `machine` - Power, speeds and origin of the machine.
+///
+/// # Returns
+/// * The G-code program.
+pub fn geometry_gcode(
+    geometry: &Geometry,
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+) -> String {
+    lines_gcode(&geometry.layers, config, machine)
+}
+
+/// Write layers of subpaths as a G-code program.
+///
+/// Layers are drawn one after another. Each subpath is drawn with the laser
+/// on, with the laser turned off for the moves between them. `M4` (dynamic
+/// power) is used so that GRBL lowers the power while the head slows down for
+/// corners. The shapes of filled layers are filled with lines
+/// `machine.fill_spacing` apart after their outline is drawn.
+fn lines_gcode(
+    layers: &[Layer],
+    config: &SinusoidShadingConfig,
+    machine: &MachineProfile,
+) -> String {
+    // Millimetres per drawing unit
+    let (width_mm, height_mm) = config.physical_size_mm();
+    let scale_x = width_mm / config.width as f32;
+    let scale_y = height_mm / config.height as f32;
+
+    // Drawing coordinates have y pointing down from the top left corner.
+    // Machine coordinates grow away from the origin corner.
+    let to_machine = |&(x, y): &(f32, f32)| {
+        let (x, y) = (x * scale_x, y * scale_y);
+        match machine.origin {
+            Origin::TopLeft => (x, y),
+            Origin::TopRight => (width_mm - x, y),
+            Origin::BottomLeft => (x, height_mm - y),
+            Origin::BottomRight => (width_mm - x, height_mm - y),
+        }
+    };
+
+    let power = (machine.max_power * machine.power / 100.).round();
+
+    // Writing to a String can't fail, so the results are ignored
+    let mut gcode = String::new();
+    let _ = writeln!(
+        gcode,
+        "; img2laser {} for {}",
+        config.method.description(),
+        machine.name
+    );
+    let _ = writeln!(
+        gcode,
+        "; {:.1} x {:.1} mm, {} lines",
+        width_mm,
+        height_mm,
+        layers
+            .iter()
+            .map(|layer| layer.subpaths.len())
+            .sum::<usize>()
+    );
+    let _ = writeln!(gcode, "G21 ; millimetres");
+    let _ = writeln!(gcode, "G90 ; absolute coordinates");
+    let _ = writeln!(gcode, "M5");
+
+    for layer in layers {
+        if layers.len() > 1 || layer.stroke.is_some() {
+            let _ = writeln!(gcode, "; layer {}", layer.name);
+        }
+
+        for subpath in &layer.subpaths {
+            // Dots are burnt by holding the laser still. `M4` turns the laser
+            // off while the head isn't moving, so they use constant power.
+            if subpath.is_point() {
+                let (x, y) = to_machine(&subpath.start.position());
+                let _ = writeln!(gcode, "G0 X{:.3} Y{:.3} F{}", x, y, machine.travel_rate);
+                let _ = writeln!(gcode, "M3 S{}", power);
+                let _ = writeln!(gcode, "G4 P{:.3}", machine.dwell / 1000.);
+                let _ = writeln!(gcode, "M5");
+                continue;
+            }
+
+            // Curves are drawn as short straight moves
+            let line: Vec<_> = subpath.flatten().iter().map(to_machine).collect();
+            burn(&mut gcode, &line, power, machine);
+
+            if layer.filled {
+                for (start, end) in fill_lines(&line, machine.fill_spacing) {
+                    burn(&mut gcode, &[start, end], power, machine);
+                }
+            }
+        }
+    }
+
+    let _ = writeln!(gcode, "G0 X0 Y0 F{}", machine.travel_rate);
+    let _ = writeln!(gcode, "M2");
+    gcode
+}
+
+/// Horizontal lines that fill a closed polygon, by the even-odd rule.
+///
+/// The lines are `spacing` apart and centred on the polygon, so a shape
+/// narrower than `spacing` still gets a line through its middle. Every other
+/// row runs backwards, so the head doesn't have to go back across the shape.
+///
+/// # Arguments
+/// * `polygon` - The corners of the polygon. It is closed from the last
+///   corner back to the first.
+/// * `spacing` - Distance between the lines.
+///
+/// # Returns
+/// * The start and end of each line.
+fn fill_lines(polygon: &[(f32, f32)], spacing: f32) -> Vec<((f32, f32), (f32, f32))> {
+    let ys = polygon.iter().map(|&(_, y)| y);
+    let (top, bottom) = ys.fold((f32::INFINITY, f32::NEG_INFINITY), |(top, bottom), y| {
+        (top.min(y), bottom.max(y))
+    });
+    if polygon.len() < 3 || spacing <= 0. || top >= bottom {
+        return Vec::new();
+    }
+
+    let rows = ((bottom - top) / spacing) as usize + 1;
+    let first = (top + bottom) / 2. - (rows - 1) as f32 * spacing / 2.;
+
+    let mut lines = Vec::new();
+    for row in 0..rows {
+        let y = first + row as f32 * spacing;
+
+        // Where the edges cross the row, from left to right
+        let mut crossings: Vec<f32> = polygon
+            .iter()
+            .zip(polygon.iter().cycle().skip(1))
+            .filter(|(a, b)| (a.1 <= y) != (b.1 <= y))
+            .map(|(a, b)| a.0 + (y - a.1) * (b.0 - a.0) / (b.1 - a.1))
+            .collect();
+        crossings.sort_by(f32::total_cmp);
+
+        let spans = crossings.chunks_exact(2).map(|x| ((x[0], y), (x[1], y)));
+        if row % 2 == 1 {
+            lines.extend(spans.rev().map(|(start, end)| (end, start)));
+        } else {
+            lines.extend(spans);
+        }
+    }
+
+    lines
+}
+
+/// Draw a polyline with the laser on, moving to its start with the laser off.
+fn burn(gcode: &mut String, line: &[(f32, f32)], power: f32, machine: &MachineProfile) {
+    let Some((&(x, y), rest)) = line.split_first() else {
+        return;
+    };
+
+    let _ = writeln!(gcode, "G0 X{:.3} Y{:.3} F{}", x, y, machine.travel_rate);
+    let _ = writeln!(gcode, "M4 S{}", power);
+    let _ = writeln!(gcode, "G1 F{}", machine.feed_rate);
+    for (x, y) in rest {
+        let _ = writeln!(gcode, "G1 X{:.3} Y{:.3}", x, y);
+    }
+    let _ = writeln!(gcode, "M5");
+}
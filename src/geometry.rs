@@ -0,0 +1,271 @@
+use std::f32::consts::PI;
+
+/// Number of straight segments a cubic curve is split into by `flatten`
+const CURVE_STEPS: usize = 16;
+
+/// Number of sides of the polygon drawn for a circle
+const CIRCLE_SIDES: usize = 24;
+
+/// The vector output of a shading method, before it is written as SVG or
+/// G-code.
+///
+/// Coordinates are in drawing units, within the `Canvas` the geometry was
+/// shaded for, with the y-axis pointing down.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Geometry {
+    /// Layers, drawn in order
+    pub layers: Vec<Layer>,
+}
+
+impl Geometry {
+    /// Geometry with a single layer.
+    pub fn single(layer: Layer) -> Self {
+        Geometry {
+            layers: vec![layer],
+        }
+    }
+}
+
+/// A set of subpaths that are drawn together, e.g. one laser pass or one pen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+    /// Name of the layer, used for SVG groups and G-code comments
+    pub name: String,
+
+    /// Stroke colour, if it differs from the configured one
+    pub stroke: Option<String>,
+
+    /// The subpaths. The laser is turned off (or the pen lifted) between
+    /// them.
+    pub subpaths: Vec<Subpath>,
+
+    /// Whether the subpaths are closed shapes to be filled, e.g. halftone
+    /// dots
+    pub filled: bool,
+}
+
+impl Layer {
+    /// Create a layer of polylines with the configured stroke.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the layer.
+    /// * `lines` - The polylines, as lists of (x, y) points. Empty ones are
+    ///   left out.
+    pub fn new(name: impl Into<String>, lines: Vec<Vec<(f32, f32)>>) -> Self {
+        let subpaths = lines
+            .into_iter()
+            .filter_map(|line| Subpath::polyline(line.into_iter().map(Vertex::from)))
+            .collect();
+        Layer::from_subpaths(name, subpaths)
+    }
+
+    /// Create a layer of polylines that are closed shapes to be filled.
+    pub fn filled(name: impl Into<String>, lines: Vec<Vec<(f32, f32)>>) -> Self {
+        Layer {
+            filled: true,
+            ..Layer::new(name, lines)
+        }
+    }
+
+    /// Create a layer of subpaths with the configured stroke.
+    pub fn from_subpaths(name: impl Into<String>, subpaths: Vec<Subpath>) -> Self {
+        Layer {
+            name: name.into(),
+            stroke: None,
+            subpaths,
+            filled: false,
+        }
+    }
+}
+
+/// A point of a subpath.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vertex {
+    pub x: f32,
+    pub y: f32,
+
+    /// Darkness of the image at this point, from 0 (white) to 1 (black), for
+    /// methods that know it. Backends may use it to vary power or speed.
+    pub intensity: Option<f32>,
+}
+
+impl Vertex {
+    /// A vertex without an intensity.
+    pub fn new(x: f32, y: f32) -> Self {
+        Vertex {
+            x,
+            y,
+            intensity: None,
+        }
+    }
+
+    /// A vertex with the darkness of the image under it.
+    pub fn with_intensity(x: f32, y: f32, intensity: f32) -> Self {
+        Vertex {
+            intensity: Some(intensity),
+            ..Vertex::new(x, y)
+        }
+    }
+
+    /// The (x, y) position.
+    pub fn position(&self) -> (f32, f32) {
+        (self.x, self.y)
+    }
+}
+
+impl From<(f32, f32)> for Vertex {
+    fn from((x, y): (f32, f32)) -> Self {
+        Vertex::new(x, y)
+    }
+}
+
+/// One piece of a subpath, from the end of the previous one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Segment {
+    /// A straight line
+    Line(Vertex),
+
+    /// A cubic Bézier curve
+    Cubic {
+        control1: (f32, f32),
+        control2: (f32, f32),
+        to: Vertex,
+    },
+}
+
+impl Segment {
+    /// The vertex the segment ends at.
+    pub fn end(&self) -> Vertex {
+        match *self {
+            Segment::Line(to) | Segment::Cubic { to, .. } => to,
+        }
+    }
+}
+
+/// A connected run of lines and curves, drawn without turning the laser off.
+///
+/// A subpath without any segments is a point, e.g. a stipple. Points are
+/// drawn as dots of `SinusoidShadingConfig::dot_size`, or burnt by holding
+/// the laser still.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Subpath {
+    /// Where the subpath starts
+    pub start: Vertex,
+
+    /// The lines and curves, in order
+    pub segments: Vec<Segment>,
+
+    /// Whether the subpath ends with a line back to `start`
+    pub closed: bool,
+}
+
+impl Subpath {
+    /// A subpath of straight lines through `points`.
+    ///
+    /// # Returns
+    /// * The subpath, or `None` if there are no points.
+    pub fn polyline(points: impl IntoIterator<Item = Vertex>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let start = points.next()?;
+        Some(Subpath {
+            start,
+            segments: points.map(Segment::Line).collect(),
+            closed: false,
+        })
+    }
+
+    /// A single point.
+    pub fn point(vertex: Vertex) -> Self {
+        Subpath {
+            start: vertex,
+            segments: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Whether the subpath is a single point.
+    pub fn is_point(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Whether every segment is a straight line.
+    pub fn is_polyline(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Line(_)))
+    }
+
+    /// The start vertex followed by the end of each segment.
+    pub fn vertices(&self) -> impl Iterator<Item = Vertex> + '_ {
+        std::iter::once(self.start).chain(self.segments.iter().map(Segment::end))
+    }
+
+    /// Approximate the subpath with straight lines, for outputs that can't
+    /// draw curves.
+    ///
+    /// # Returns
+    /// * The (x, y) points of the polyline. A closed subpath ends back at its
+    ///   start.
+    pub fn flatten(&self) -> Vec<(f32, f32)> {
+        let mut points = vec![self.start.position()];
+        let mut from = self.start.position();
+        for segment in &self.segments {
+            if let Segment::Cubic {
+                control1, control2, ..
+            } = *segment
+            {
+                let to = segment.end().position();
+                points.extend((1..CURVE_STEPS).map(|i| {
+                    cubic_point(from, control1, control2, to, i as f32 / CURVE_STEPS as f32)
+                }));
+            }
+
+            from = segment.end().position();
+            points.push(from);
+        }
+
+        if self.closed {
+            points.push(self.start.position());
+        }
+        points
+    }
+
+    /// Same as `flatten`, but a point is drawn as a circle, for outputs that
+    /// can't draw dots.
+    ///
+    /// # Arguments
+    /// * `dot_radius` - Radius of the circle drawn for a point.
+    pub fn outline(&self, dot_radius: f32) -> Vec<(f32, f32)> {
+        if self.is_point() {
+            circle(self.start.position(), dot_radius)
+        } else {
+            self.flatten()
+        }
+    }
+}
+
+/// A circle as a closed polygon.
+pub(crate) fn circle(centre: (f32, f32), radius: f32) -> Vec<(f32, f32)> {
+    (0..=CIRCLE_SIDES)
+        .map(|i| {
+            let (sin, cos) = (2. * PI * i as f32 / CIRCLE_SIDES as f32).sin_cos();
+            (centre.0 + radius * cos, centre.1 + radius * sin)
+        })
+        .collect()
+}
+
+/// Point at `t` (0 to 1) along a cubic Bézier curve.
+fn cubic_point(
+    p0: (f32, f32),
+    p1: (f32, f32),
+    p2: (f32, f32),
+    p3: (f32, f32),
+    t: f32,
+) -> (f32, f32) {
+    let s = 1. - t;
+    let (a, b, c, d) = (s * s * s, 3. * s * s * t, 3. * s * t * t, t * t * t);
+    (
+        a * p0.0 + b * p1.0 + c * p2.0 + d * p3.0,
+        a * p0.1 + b * p1.1 + c * p2.1 + d * p3.1,
+    )
+}
@@ -0,0 +1,205 @@
+use std::f32::consts::PI;
+
+use clap::ValueEnum;
+
+use image::{DynamicImage, GrayImage};
+
+use crate::area::AreaAverages;
+use crate::crosshatch::directions;
+use crate::geometry::{circle, Layer, Subpath, Vertex};
+use crate::parallel;
+use crate::sinusoid::{darkness, Tracker};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Dots smaller than this fraction of a cell are left out
+const MIN_DOT: f32 = 0.01;
+
+/// Shape of the halftone dots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HalftoneShape {
+    /// Round dots
+    #[default]
+    Circle,
+
+    /// Square dots, turned with the grid
+    Square,
+
+    /// Bars across each cell, which join up into lines of varying thickness
+    Line,
+}
+
+value_enum_strings!(HalftoneShape);
+
+/// Place halftone dots over a grayscale image, at `config.halftone_angle`.
+///
+/// # Returns
+/// * A single layer of filled dots, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_halftone(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let halftone = Halftone::new(&img.to_luma8(), config);
+    let angle = config.halftone_angle;
+    let tracker = Tracker::new(progress, cancel, halftone.row_count(angle));
+
+    let dots = halftone
+        .trace(angle, &tracker)
+        .ok_or(ImageProcessError::Cancelled)?;
+    Ok(vec![Layer {
+        filled: true,
+        ..Layer::from_subpaths("halftone", dots)
+    }])
+}
+
+/// Places halftone dots on a rotated grid.
+///
+/// Each cell of the grid is as tall as the space between sinusoids would be,
+/// so `lines` sets the screen's resolution for both methods. The area of each
+/// dot is proportional to the darkness of its cell.
+pub(crate) struct Halftone<'a> {
+    config: &'a SinusoidShadingConfig,
+
+    /// The image to sample
+    area: AreaAverages,
+}
+
+impl<'a> Halftone<'a> {
+    /// Prepare an image for halftoning.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `config` - The configuration struct.
+    pub(crate) fn new(luma: &GrayImage, config: &'a SinusoidShadingConfig) -> Self {
+        Halftone {
+            config,
+            area: AreaAverages::new(luma, config.gamma),
+        }
+    }
+
+    /// Number of rows in a grid at `angle` degrees, for progress reporting.
+    pub(crate) fn row_count(&self, angle: f32) -> usize {
+        let (direction, normal) = directions(angle);
+        let (rows, _) = self.grid_size(direction, normal);
+        rows
+    }
+
+    /// Place the dots of a grid at `angle` degrees.
+    ///
+    /// # Returns
+    /// * The outline of each dot, or `None` if it was cancelled. The vertices
+    ///   carry the darkness of the dot's cell.
+    pub(crate) fn trace(&self, angle: f32, tracker: &Tracker) -> Option<Vec<Subpath>> {
+        let config = self.config;
+        let (width, height) = (config.width as f32, config.height as f32);
+        let (direction, normal) = directions(angle);
+        let (rows, cols) = self.grid_size(direction, normal);
+        let cell = self.cell_size();
+
+        // Image pixels per output unit
+        let (img_width, img_height) = self.area.dimensions();
+        let (px_x, px_y) = (img_width as f32 / width, img_height as f32 / height);
+
+        let dots = parallel::map_range(rows, |row| {
+            if tracker.cancelled() {
+                return None;
+            }
+
+            // The grid is centred on the output, so it is symmetric
+            let v = (row as f32 - rows as f32 / 2. + 0.5) * cell;
+            let dots = (0..cols)
+                .filter_map(|col| {
+                    let u = (col as f32 - cols as f32 / 2. + 0.5) * cell;
+                    let centre = (
+                        width / 2. + direction.0 * u + normal.0 * v,
+                        height / 2. + direction.1 * u + normal.1 * v,
+                    );
+                    if !(0. ..=width).contains(&centre.0) || !(0. ..=height).contains(&centre.1) {
+                        return None;
+                    }
+
+                    let half = cell / 2.;
+                    let value = self.area.average(
+                        (centre.0 - half) * px_x,
+                        (centre.1 - half) * px_y,
+                        (centre.0 + half) * px_x,
+                        (centre.1 + half) * px_y,
+                    );
+
+                    let dark = darkness(value);
+                    if dark < MIN_DOT {
+                        return None;
+                    }
+
+                    let outline = self.dot(centre, dark, direction, normal);
+                    Subpath::polyline(
+                        outline
+                            .into_iter()
+                            .map(|(x, y)| Vertex::with_intensity(x, y, dark)),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            tracker.line_done();
+            Some(dots)
+        });
+
+        dots.into_iter()
+            .collect::<Option<Vec<_>>>()
+            .map(|dots| dots.concat())
+    }
+
+    /// Distance between neighbouring dots.
+    fn cell_size(&self) -> f32 {
+        self.config.height as f32 / self.config.line_count() as f32
+    }
+
+    /// Number of rows and columns needed to cover the output with a grid
+    /// turned to `direction`.
+    fn grid_size(&self, direction: (f32, f32), normal: (f32, f32)) -> (usize, usize) {
+        let (width, height) = (self.config.width as f32, self.config.height as f32);
+        let cell = self.cell_size();
+        let span =
+            |(x, y): (f32, f32)| ((x.abs() * width + y.abs() * height) / cell).ceil() as usize;
+        (span(normal), span(direction))
+    }
+
+    /// Outline of one dot.
+    ///
+    /// # Arguments
+    /// * `centre` - Centre of the cell.
+    /// * `dark` - Darkness of the cell, from 0 to 1.
+    /// * `direction` - Along the grid's rows.
+    /// * `normal` - Across the grid's rows.
+    fn dot(
+        &self,
+        centre: (f32, f32),
+        dark: f32,
+        direction: (f32, f32),
+        normal: (f32, f32),
+    ) -> Vec<(f32, f32)> {
+        let cell = self.cell_size();
+        let at = |u: f32, v: f32| {
+            (
+                centre.0 + direction.0 * u + normal.0 * v,
+                centre.1 + direction.1 * u + normal.1 * v,
+            )
+        };
+
+        // Rectangle of `length` along the row by `thickness` across it
+        let rectangle = |length: f32, thickness: f32| {
+            let (u, v) = (length / 2., thickness / 2.);
+            vec![at(-u, -v), at(u, -v), at(u, v), at(-u, v), at(-u, -v)]
+        };
+
+        match self.config.halftone_shape {
+            HalftoneShape::Circle => circle(centre, cell * (dark / PI).sqrt()),
+            HalftoneShape::Square => {
+                let side = cell * dark.sqrt();
+                rectangle(side, side)
+            }
+            HalftoneShape::Line => rectangle(cell, cell * dark),
+        }
+    }
+}
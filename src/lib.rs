@@ -1,5 +1,77 @@
+/// `Display` and `FromStr` for a `clap::ValueEnum`, using the same names as
+/// the command line.
+macro_rules! value_enum_strings {
+    ($type:ty) => {
+        impl std::fmt::Display for $type {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                let value =
+                    clap::ValueEnum::to_possible_value(self).expect("no variants are skipped");
+                f.write_str(value.get_name())
+            }
+        }
+
+        impl std::str::FromStr for $type {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                <$type as clap::ValueEnum>::from_str(s, true)
+            }
+        }
+    };
+}
+
+mod area;
+mod cache;
+mod card;
 mod components;
+mod contour;
+mod crosshatch;
+mod curve;
+mod dither;
+mod flow;
+mod font;
+mod gcode;
+mod geometry;
+mod halftone;
+mod machine;
+mod method;
+mod output;
+mod parallel;
+mod presets;
+mod raster;
+mod ridgeline;
+mod separation;
+mod sheet;
 mod sinusoid;
+mod stipple;
+mod tsp;
+mod worker;
 
-pub use components::{DownloadButton, FileInput, NumberInput, SinusoidSvg, SliderInput};
-pub use sinusoid::{process_image, ImageProcessError, SinusoidShadingConfig};
+pub use cache::ShadingCache;
+pub use card::{test_card, TestCard};
+pub use components::{
+    DownloadButton, FileInput, ImagePreview, NumberInput, SelectInput, SinusoidSvg, SliderInput,
+};
+pub use contour::{ContourFill, ContourSpacing};
+pub use crosshatch::HatchLayer;
+pub use curve::{Curve, CurveDetail};
+pub use dither::{dither_image, write_bitmap, Dither, RasterFormat};
+pub use flow::Modulation;
+pub use gcode::{
+    geometry_gcode, geometry_gcode_layers, process_image_gcode, process_image_gcode_layers,
+    process_image_gcode_layers_with_progress, process_image_gcode_with_progress,
+};
+pub use geometry::{Geometry, Layer, Segment, Subpath, Vertex};
+pub use halftone::HalftoneShape;
+pub use machine::{MachineProfile, Origin, OutputFormat, BUILTIN_MACHINES};
+pub use method::{shade, shade_with_progress, BuiltinShading, Canvas, ShadingMethod, Sinusoid};
+pub use output::{geometry_document, GcodeBackend, OutputBackend, SvgBackend};
+pub use presets::{builtin_preset, BUILTIN_PRESETS};
+pub use raster::render_preview;
+pub use separation::Separation;
+pub use sheet::{contact_sheet, contact_sheet_preview, Sweep};
+pub use sinusoid::{
+    image_stats, process_image, process_image_with_progress, CancellationToken, ImageProcessError,
+    ImageStats, Length, Method, SinusoidShadingConfig, Units,
+};
+pub use worker::{run_if_worker, BackgroundShader};
@@ -0,0 +1,268 @@
+use clap::ValueEnum;
+
+use crate::sinusoid::for_each_setting;
+use crate::{ImageProcessError, Length, SinusoidShadingConfig, Units};
+
+/// Corner of the bed where the machine's origin is
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Origin {
+    TopLeft,
+    TopRight,
+    #[default]
+    BottomLeft,
+    BottomRight,
+}
+
+/// File format to write
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// SVG, for laser software such as LightBurn or a printer driver
+    #[default]
+    Svg,
+
+    /// G-code, for sending straight to GRBL style controllers
+    Gcode,
+}
+
+impl OutputFormat {
+    /// The usual file extension for the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Svg => "svg",
+            OutputFormat::Gcode => "gcode",
+        }
+    }
+}
+
+value_enum_strings!(Origin);
+value_enum_strings!(OutputFormat);
+
+/// Name and text of every built-in machine profile. See
+/// `MachineProfile::parse` for the format.
+pub const BUILTIN_MACHINES: &[(&str, &str)] = &[
+    (
+        "epilog-zing",
+        "name = epilog-zing
+bed_width = 610
+bed_height = 305
+origin = top-left
+format = svg
+units = in
+stroke = #ff0000
+stroke_width = 0.001in
+",
+    ),
+    (
+        "universal-vls",
+        "name = universal-vls
+bed_width = 610
+bed_height = 457
+origin = top-left
+format = svg
+units = in
+stroke = #ff0000
+stroke_width = 0.001in
+",
+    ),
+    (
+        "glowforge",
+        "name = glowforge
+bed_width = 495
+bed_height = 279
+origin = top-left
+format = svg
+units = mm
+stroke = #0000ff
+stroke_width = 0.001in
+",
+    ),
+    (
+        "grbl",
+        "# A typical diode laser on a GRBL controller with laser mode enabled
+name = grbl
+bed_width = 400
+bed_height = 400
+origin = bottom-left
+format = gcode
+units = mm
+stroke = black
+stroke_width = 0.1mm
+max_power = 1000
+power = 100
+feed_rate = 1000
+travel_rate = 3000
+",
+    ),
+];
+
+/// Settings of a particular laser cutter or engraver.
+///
+/// Profiles are written like presets, with one `field = value` per line:
+///
+/// ```text
+/// name = shop-diode
+/// bed_width = 400
+/// bed_height = 400
+/// origin = bottom-left
+/// format = gcode
+/// power = 40
+/// ```
+///
+/// Fields that are left out keep their default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineProfile {
+    /// Name shown to the user
+    pub name: String,
+
+    /// Size of the work area in millimetres, if known
+    pub bed: Option<(f32, f32)>,
+
+    /// Corner of the bed where the origin is. The G-code output is placed in
+    /// this corner.
+    pub origin: Origin,
+
+    /// Preferred output format
+    pub format: OutputFormat,
+
+    /// Units the machine's software works in, if known. A physical size is
+    /// taken to be in these units.
+    pub units: Option<Units>,
+
+    /// Stroke colour the machine's software expects, if any
+    pub stroke: Option<String>,
+
+    /// Stroke width the machine's software expects, if any
+    pub stroke_width: Option<Length>,
+
+    /// Spindle speed (`S` value) for full laser power
+    pub max_power: f32,
+
+    /// Laser power for the lines, in percent of `max_power`
+    pub power: f32,
+
+    /// Speed while drawing lines, in mm/min
+    pub feed_rate: f32,
+
+    /// Speed while moving between lines, in mm/min
+    pub travel_rate: f32,
+
+    /// Time the laser is held on each dot (e.g. stipples), in milliseconds
+    pub dwell: f32,
+
+    /// Spacing of the lines that fill closed shapes (e.g. halftone dots), in
+    /// millimetres. About the width of the laser's line.
+    pub fill_spacing: f32,
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        MachineProfile {
+            name: "generic".to_string(),
+            bed: None,
+            origin: Origin::BottomLeft,
+            format: OutputFormat::Svg,
+            units: None,
+            stroke: None,
+            stroke_width: None,
+            // GRBL's default for $30
+            max_power: 1000.,
+            power: 100.,
+            feed_rate: 1000.,
+            travel_rate: 3000.,
+            dwell: 20.,
+            fill_spacing: 0.1,
+        }
+    }
+}
+
+impl MachineProfile {
+    /// Read a profile.
+    ///
+    /// # Arguments
+    /// * `profile` - The profile text.
+    ///
+    /// # Returns
+    /// * The profile, or an error naming the first line that couldn't be read.
+    pub fn parse(profile: &str) -> Result<Self, ImageProcessError> {
+        let mut machine = MachineProfile::default();
+        let (mut bed_width, mut bed_height) = (None, None);
+
+        for_each_setting(profile, |field, value| {
+            let invalid = || format!("invalid value `{}`", value);
+            let number = || value.parse::<f32>().map_err(|_| invalid());
+
+            match field {
+                "name" => machine.name = value.to_string(),
+                "bed_width" => bed_width = Some(number()?),
+                "bed_height" => bed_height = Some(number()?),
+                "origin" => machine.origin = value.parse().map_err(|_| invalid())?,
+                "format" => machine.format = value.parse().map_err(|_| invalid())?,
+                "units" => machine.units = Some(value.parse().map_err(|_| invalid())?),
+                "stroke" => machine.stroke = Some(value.to_string()),
+                "stroke_width" => machine.stroke_width = Some(value.parse()?),
+                "max_power" => machine.max_power = number()?,
+                "power" => machine.power = number()?,
+                "feed_rate" => machine.feed_rate = number()?,
+                "travel_rate" => machine.travel_rate = number()?,
+                "dwell" => machine.dwell = number()?,
+                "fill_spacing" => machine.fill_spacing = number()?,
+                _ => return Err(format!("unknown field `{}`", field)),
+            }
+            Ok(())
+        })?;
+
+        machine.bed = bed_width.zip(bed_height);
+        Ok(machine)
+    }
+
+    /// Find a built-in profile by name.
+    pub fn builtin(name: &str) -> Option<Self> {
+        BUILTIN_MACHINES
+            .iter()
+            .find(|(machine_name, _)| *machine_name == name)
+            .map(|(_, profile)| Self::parse(profile).expect("built-in profiles are valid"))
+    }
+
+    /// Apply the machine's units and stroke conventions to a configuration.
+    ///
+    /// # Arguments
+    /// * `config` - The configuration to change.
+    /// * `is_set` - Whether the user chose a field themselves, e.g. on the
+    ///   command line, by its name in `SinusoidShadingConfig::FIELDS`. Those
+    ///   fields are kept.
+    pub fn apply(&self, config: &mut SinusoidShadingConfig, is_set: impl Fn(&str) -> bool) {
+        if let Some(stroke) = self.stroke.as_ref().filter(|_| !is_set("stroke")) {
+            config.stroke = stroke.clone();
+        }
+        if let Some(stroke_width) = self.stroke_width.filter(|_| !is_set("stroke_width")) {
+            config.stroke_width = stroke_width;
+        }
+
+        // Without a physical size, `width` and `height` would be taken to be
+        // in the units, so the output keeps its size in pixels instead
+        let sized = config.physical_width.is_some() || config.physical_height.is_some();
+        if let Some(units) = self.units.filter(|_| sized && !is_set("units")) {
+            config.units = units;
+        }
+    }
+
+    /// Check that the output of `config` fits on the bed.
+    ///
+    /// # Returns
+    /// * A warning describing the problem, if it doesn't fit.
+    pub fn check_fits(&self, config: &SinusoidShadingConfig) -> Option<String> {
+        let (bed_width, bed_height) = self.bed?;
+        let (width, height) = config.physical_size_mm();
+
+        // Allow for rounding in the physical size
+        let tolerance = 0.01;
+        if width <= bed_width + tolerance && height <= bed_height + tolerance {
+            return None;
+        }
+
+        Some(format!(
+            "the output is {:.1} x {:.1} mm, larger than the {} x {} mm bed of {}",
+            width, height, bed_width, bed_height, self.name
+        ))
+    }
+}
@@ -1,14 +1,11 @@
 #![allow(non_snake_case)]
-use std::io::Cursor;
-
-use base64::{engine::general_purpose, Engine as _};
-
 use dioxus::prelude::*;
 use dioxus_web::Config;
 
-use image::{self, DynamicImage, GenericImageView, ImageOutputFormat};
+use image::{self, GenericImageView};
 use img2laser::{
-    DownloadButton, FileInput, NumberInput, SinusoidShadingConfig, SinusoidSvg, SliderInput,
+    builtin_preset, BackgroundShader, DownloadButton, FileInput, ImagePreview, MachineProfile,
+    NumberInput, SelectInput, SinusoidShadingConfig, SinusoidSvg, SliderInput,
 };
 
 const IMAGE: &[u8] = include_bytes!("../examples/example_1.png");
@@ -18,15 +15,13 @@ struct RootProps {
     img: image::DynamicImage,
 }
 
-fn image_to_base64(img: &DynamicImage) -> String {
-    let mut image_data: Vec<u8> = Vec::new();
-    img.write_to(&mut Cursor::new(&mut image_data), ImageOutputFormat::Png)
-        .unwrap();
-    let res_base64 = general_purpose::STANDARD.encode(image_data);
-    format!("data:image/png;base64,{}", res_base64)
-}
-
 fn main() {
+    // The shading worker runs this same program. In there, answer shading
+    // jobs instead of launching the app.
+    if img2laser::run_if_worker() {
+        return;
+    }
+
     // Open image
     let img = image::load_from_memory(IMAGE).expect("Couldn't load image");
 
@@ -43,12 +38,26 @@ fn App(cx: Scope<RootProps>) -> Element {
         ..Default::default()
     };
 
-    use_shared_state_provider(cx, || cx.props.img.clone());
+    use_shared_state_provider(cx, || BackgroundShader::new(&cx.props.img));
+    use_shared_state_provider(cx, || ImagePreview::new(&cx.props.img));
     use_shared_state_provider(cx, || config);
 
-    let img = use_shared_state::<DynamicImage>(cx).unwrap();
+    let preview = use_shared_state::<ImagePreview>(cx).unwrap();
     let config = use_shared_state::<SinusoidShadingConfig>(cx).unwrap();
 
+    // The selected machine, and whether the output fits on its bed
+    let machine = use_state(cx, || None::<MachineProfile>);
+    let bed_warning = machine
+        .get()
+        .as_ref()
+        .and_then(|m| m.check_fits(&config.read()))
+        .unwrap_or_default();
+    let bed_warning_display = if bed_warning.is_empty() {
+        "none"
+    } else {
+        "block"
+    };
+
     render! {
         header {
             h1 {
@@ -67,6 +76,27 @@ fn App(cx: Scope<RootProps>) -> Element {
                         label: "Select image file: ".to_string(),
                     }
                 },
+                div {
+                    class: "select-input",
+                    SelectInput {
+                        id: "method".to_string(),
+                        label: "Method: ".to_string(),
+                        options: vec![
+                            "sinusoid".to_string(),
+                            "halftone".to_string(),
+                            "stipple".to_string(),
+                            "tsp".to_string(),
+                            "flow".to_string(),
+                            "ridgeline".to_string(),
+                            "contour".to_string(),
+                            "curve".to_string(),
+                        ],
+                        value: config.read().method.to_string(),
+                        on_input: move |event: FormEvent| {
+                            config.with_mut(|c| c.set_field("method", &event.value.clone()))
+                        }
+                    }
+                },
                 div {
                     class: "number-input",
                     NumberInput {
@@ -86,6 +116,27 @@ fn App(cx: Scope<RootProps>) -> Element {
                         step: 1,
                     }
                 },
+                div {
+                    class: "select-input",
+                    SelectInput {
+                        id: "units".to_string(),
+                        label: "Units: ".to_string(),
+                        options: vec!["px".to_string(), "mm".to_string(), "in".to_string()],
+                        value: config.read().units.to_string(),
+                        on_input: move |event: FormEvent| {
+                            config.with_mut(|c| c.set_field("units", &event.value.clone()))
+                        }
+                    }
+                },
+                div {
+                    class: "number-input",
+                    NumberInput {
+                        id: "physical_width".to_string(),
+                        label: "Physical width: ".to_string(),
+                        min: 0.,
+                        step: 0.1,
+                    }
+                },
                 div {
                     class: "slider-input",
                     SliderInput {
@@ -160,6 +211,108 @@ fn App(cx: Scope<RootProps>) -> Element {
                         }
                     }
                 },
+                div {
+                    class: "select-input",
+                    SelectInput {
+                        id: "halftone_shape".to_string(),
+                        label: "Halftone shape: ".to_string(),
+                        options: vec!["circle".to_string(), "square".to_string(), "line".to_string()],
+                        value: config.read().halftone_shape.to_string(),
+                        on_input: move |event: FormEvent| {
+                            config.with_mut(|c| c.set_field("halftone_shape", &event.value.clone()))
+                        }
+                    }
+                },
+                div {
+                    class: "slider-input",
+                    SliderInput {
+                        id: "halftone_angle".to_string(),
+                        label: "Halftone angle: ".to_string(),
+                        min: 0.,
+                        max: 90.,
+                        value: config.read().halftone_angle,
+                        step: 5.,
+                        on_input: move |event: FormEvent| {
+                            config.with_mut(|c| c.set_field("halftone_angle", &event.value.clone()))
+                        }
+                    }
+                },
+                div {
+                    class: "number-input",
+                    NumberInput {
+                        id: "stipple_points".to_string(),
+                        label: "Stipple dots: ".to_string(),
+                        min: 1,
+                        step: 100,
+                    }
+                },
+                div {
+                    class: "slider-input",
+                    SliderInput {
+                        id: "gamma".to_string(),
+                        label: "Gamma: ".to_string(),
+                        min: 0.2,
+                        max: 3.,
+                        value: config.read().gamma,
+                        step: 0.1,
+                        on_input: move |event: FormEvent| {
+                            config.with_mut(|c| c.set_field("gamma", &event.value.clone()))
+                        }
+                    }
+                },
+                div {
+                    class: "select-input",
+                    SelectInput {
+                        id: "machine".to_string(),
+                        label: "Machine: ".to_string(),
+                        options: std::iter::once("none".to_string())
+                            .chain(img2laser::BUILTIN_MACHINES.iter().map(|(name, _)| name.to_string()))
+                            .collect(),
+                        value: machine.get().as_ref().map(|m| m.name.clone()).unwrap_or_default(),
+                        on_input: move |event: FormEvent| {
+                            let profile = MachineProfile::builtin(&event.value);
+                            if let Some(profile) = &profile {
+                                config.with_mut(|c| profile.apply(c, |_| false));
+                            }
+                            machine.set(profile);
+                        }
+                    },
+                    p {
+                        class: "warning",
+                        style: "display: {bed_warning_display};",
+                        "Warning: {bed_warning}"
+                    }
+                },
+                div {
+                    class: "select-input",
+                    SelectInput {
+                        id: "stroke_preset".to_string(),
+                        label: "Line style: ".to_string(),
+                        options: img2laser::BUILTIN_PRESETS
+                            .iter()
+                            .map(|(name, _)| name.to_string())
+                            .collect(),
+                        value: String::new(),
+                        on_input: move |event: FormEvent| {
+                            if let Some(preset) = builtin_preset(&event.value) {
+                                // Built-in presets are always valid
+                                config.with_mut(|c| c.apply_preset(preset).unwrap());
+                            }
+                        }
+                    }
+                },
+                div {
+                    class: "select-input",
+                    SelectInput {
+                        id: "separation".to_string(),
+                        label: "Colour separation: ".to_string(),
+                        options: vec!["none".to_string(), "cmyk".to_string(), "rgb".to_string()],
+                        value: config.read().separation.to_string(),
+                        on_input: move |event: FormEvent| {
+                            config.with_mut(|c| c.set_field("separation", &event.value.clone()))
+                        }
+                    }
+                },
                 div {
                     DownloadButton {},
                 },
@@ -172,7 +325,7 @@ fn App(cx: Scope<RootProps>) -> Element {
             div {
                 id: "input-image-panel",
                 h2 { "Original Image" },
-                img { src: "{image_to_base64(&img.read())}" },
+                img { src: "{preview.read().0}" },
             },
         },
         footer {
@@ -0,0 +1,232 @@
+use std::borrow::Cow;
+
+use image::DynamicImage;
+
+use crate::contour::trace_contours;
+use crate::curve::trace_curve;
+use crate::flow::trace_flow;
+use crate::halftone::trace_halftone;
+use crate::ridgeline::trace_ridgelines;
+use crate::separation::{self, Separation};
+use crate::sinusoid::trace_sinusoids;
+use crate::stipple::trace_stipples;
+use crate::tsp::trace_tour;
+use crate::{CancellationToken, Geometry, ImageProcessError, Method, SinusoidShadingConfig};
+
+/// The area a shading method draws into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Canvas {
+    /// Width in drawing units
+    pub width: usize,
+
+    /// Height in drawing units
+    pub height: usize,
+}
+
+impl Canvas {
+    /// Create a canvas.
+    pub fn new(width: usize, height: usize) -> Self {
+        Canvas { width, height }
+    }
+
+    /// `config` with the size of the canvas. It is only copied if the size
+    /// differs.
+    pub(crate) fn configure<'a>(
+        &self,
+        config: &'a SinusoidShadingConfig,
+    ) -> Cow<'a, SinusoidShadingConfig> {
+        if *self == Canvas::from(config) {
+            return Cow::Borrowed(config);
+        }
+
+        Cow::Owned(SinusoidShadingConfig {
+            width: self.width,
+            height: self.height,
+            ..config.clone()
+        })
+    }
+}
+
+impl From<&SinusoidShadingConfig> for Canvas {
+    fn from(config: &SinusoidShadingConfig) -> Self {
+        Canvas::new(config.width, config.height)
+    }
+}
+
+/// An algorithm that turns an image into line art.
+///
+/// Implementations only need to produce `Geometry`. Loading the image and
+/// writing the geometry with an `OutputBackend` (SVG or G-code) is shared by
+/// every method.
+///
+/// ```no_run
+/// use img2laser::{Canvas, Geometry, Layer, ShadingMethod};
+///
+/// /// Draws a single diagonal line, whatever the image.
+/// struct Diagonal;
+///
+/// impl ShadingMethod for Diagonal {
+///     fn shade(&self, _img: &image::DynamicImage, canvas: &Canvas) -> Geometry {
+///         let end = (canvas.width as f32, canvas.height as f32);
+///         Geometry::single(Layer::new("diagonal", vec![vec![(0., 0.), end]]))
+///     }
+/// }
+/// ```
+pub trait ShadingMethod {
+    /// Shade an image.
+    ///
+    /// # Arguments
+    /// * `img` - A reference to the image.
+    /// * `canvas` - The area to fill. The image is stretched to cover it.
+    ///
+    /// # Returns
+    /// * The geometry, in drawing units within `canvas`.
+    fn shade(&self, img: &DynamicImage, canvas: &Canvas) -> Geometry;
+
+    /// Same as `shade`, but reports progress and can be cancelled. See
+    /// `process_image_with_progress`.
+    ///
+    /// Methods that can't report progress as they go don't need to implement
+    /// this. By default, `cancel` is checked once before shading and
+    /// progress is reported once afterwards.
+    fn shade_with_progress(
+        &self,
+        img: &DynamicImage,
+        canvas: &Canvas,
+        progress: &(dyn Fn(usize, usize) + Sync),
+        cancel: &CancellationToken,
+    ) -> Result<Geometry, ImageProcessError> {
+        if cancel.is_cancelled() {
+            return Err(ImageProcessError::Cancelled);
+        }
+
+        let geometry = self.shade(img, canvas);
+        progress(1, 1);
+        Ok(geometry)
+    }
+}
+
+/// The frequency modulated sinusoids, the original shading method of
+/// img2laser.
+///
+/// Each row of the image is drawn as a horizontal sinusoid, whose frequency
+/// rises with the darkness. Crosshatch layers are added as set in
+/// `config.crosshatch`. The image is shaded in grey, whatever
+/// `config.method` and `config.separation` are.
+#[derive(Clone, Copy, Debug)]
+pub struct Sinusoid<'a> {
+    pub config: &'a SinusoidShadingConfig,
+}
+
+impl<'a> Sinusoid<'a> {
+    pub fn new(config: &'a SinusoidShadingConfig) -> Self {
+        Sinusoid { config }
+    }
+}
+
+impl ShadingMethod for Sinusoid<'_> {
+    fn shade(&self, img: &DynamicImage, canvas: &Canvas) -> Geometry {
+        self.shade_with_progress(img, canvas, &|_, _| {}, &CancellationToken::new())
+            .expect("a new token is never cancelled")
+    }
+
+    fn shade_with_progress(
+        &self,
+        img: &DynamicImage,
+        canvas: &Canvas,
+        progress: &(dyn Fn(usize, usize) + Sync),
+        cancel: &CancellationToken,
+    ) -> Result<Geometry, ImageProcessError> {
+        let config = canvas.configure(self.config);
+        let layers = trace_sinusoids(img, &config, progress, cancel)?;
+        Ok(Geometry { layers })
+    }
+}
+
+/// The built-in shading methods, one for each `Method`.
+///
+/// `config.method` picks the method, e.g. `Sinusoid` for `Method::Sinusoid`.
+/// Colour separations (and crosshatching, for sinusoids) are applied as set
+/// in the configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct BuiltinShading<'a> {
+    pub config: &'a SinusoidShadingConfig,
+}
+
+impl<'a> BuiltinShading<'a> {
+    pub fn new(config: &'a SinusoidShadingConfig) -> Self {
+        BuiltinShading { config }
+    }
+}
+
+impl ShadingMethod for BuiltinShading<'_> {
+    fn shade(&self, img: &DynamicImage, canvas: &Canvas) -> Geometry {
+        self.shade_with_progress(img, canvas, &|_, _| {}, &CancellationToken::new())
+            .expect("a new token is never cancelled")
+    }
+
+    fn shade_with_progress(
+        &self,
+        img: &DynamicImage,
+        canvas: &Canvas,
+        progress: &(dyn Fn(usize, usize) + Sync),
+        cancel: &CancellationToken,
+    ) -> Result<Geometry, ImageProcessError> {
+        let config = canvas.configure(self.config);
+        if config.separation != Separation::None {
+            let layers = separation::trace_pens(img, &config, config.method, progress, cancel)?;
+            return Ok(Geometry { layers });
+        }
+
+        let trace = match config.method {
+            Method::Sinusoid => {
+                return Sinusoid::new(&config).shade_with_progress(img, canvas, progress, cancel)
+            }
+            Method::Halftone => trace_halftone,
+            Method::Stipple => trace_stipples,
+            Method::Tsp => trace_tour,
+            Method::Flow => trace_flow,
+            Method::Ridgeline => trace_ridgelines,
+            Method::Contour => trace_contours,
+            Method::Curve => trace_curve,
+        };
+        let layers = trace(img, &config, progress, cancel)?;
+
+        Ok(Geometry { layers })
+    }
+}
+
+/// Shade an image with the method selected by `config.method`.
+///
+/// This is the geometry behind `process_image`, before it is written as SVG.
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration struct.
+///
+/// # Returns
+/// * The geometry, in drawing units within `config.width` by
+///   `config.height`.
+pub fn shade(img: &DynamicImage, config: &SinusoidShadingConfig) -> Geometry {
+    shade_with_progress(img, config, |_, _| {}, &CancellationToken::new())
+        .expect("a new token is never cancelled")
+}
+
+/// Same as `shade`, but reports progress and can be cancelled. See
+/// `process_image_with_progress`.
+pub fn shade_with_progress(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: impl Fn(usize, usize) + Sync,
+    cancel: &CancellationToken,
+) -> Result<Geometry, ImageProcessError> {
+    BuiltinShading::new(config).shade_with_progress(img, &Canvas::from(config), &progress, cancel)
+}
+
+impl SinusoidShadingConfig {
+    /// The shading method selected by `method`, set up with this
+    /// configuration.
+    pub fn shading_method(&self) -> BuiltinShading<'_> {
+        BuiltinShading::new(self)
+    }
+}
@@ -0,0 +1,199 @@
+use svg::node::element::path::{Command, Data, Position};
+use svg::node::element::{Group, Path};
+use svg::Document;
+
+use crate::gcode::geometry_gcode;
+use crate::geometry::{Geometry, Layer, Segment, Subpath};
+use crate::{MachineProfile, SinusoidShadingConfig};
+
+/// A format that geometry can be written in.
+///
+/// Every shading method produces `Geometry`, so a backend works with all of
+/// them. `SvgBackend` and `GcodeBackend` are built in.
+pub trait OutputBackend {
+    /// The written output, e.g. an SVG document or a G-code program
+    type Output;
+
+    /// Write geometry.
+    ///
+    /// # Arguments
+    /// * `geometry` - The geometry, shaded for a `Canvas` of `config.width`
+    ///   by `config.height`.
+    /// * `config` - The configuration struct, for the size and line style.
+    ///
+    /// # Returns
+    /// * The output.
+    fn write(&self, geometry: &Geometry, config: &SinusoidShadingConfig) -> Self::Output;
+}
+
+/// Writes geometry as an SVG document, see `geometry_document`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SvgBackend;
+
+impl OutputBackend for SvgBackend {
+    type Output = Document;
+
+    fn write(&self, geometry: &Geometry, config: &SinusoidShadingConfig) -> Document {
+        geometry_document(geometry, config)
+    }
+}
+
+/// Writes geometry as a G-code program for a machine, see `geometry_gcode`.
+#[derive(Clone, Debug, Default)]
+pub struct GcodeBackend {
+    pub machine: MachineProfile,
+}
+
+impl GcodeBackend {
+    pub fn new(machine: MachineProfile) -> Self {
+        GcodeBackend { machine }
+    }
+}
+
+impl OutputBackend for GcodeBackend {
+    type Output = String;
+
+    fn write(&self, geometry: &Geometry, config: &SinusoidShadingConfig) -> String {
+        geometry_gcode(geometry, config, &self.machine)
+    }
+}
+
+/// Build the SVG document for the geometry of a shading method.
+///
+/// A single layer is written as one path, as it always has been. Several
+/// layers (see `SinusoidShadingConfig::crosshatch`) are each put in their own
+/// group, marked as a layer for Inkscape, so that laser software can give
+/// them separate passes.
+///
+/// # Arguments
+/// * `geometry` - The geometry, shaded for a `Canvas` of `config.width` by
+///   `config.height`.
+/// * `config` - The configuration struct, for the size and line style.
+///
+/// # Returns
+/// * An SVG document (from `svg` crate).
+pub fn geometry_document(geometry: &Geometry, config: &SinusoidShadingConfig) -> Document {
+    // Output SVG width and height
+    let width = config.width;
+    let height = config.height;
+
+    // Create the SVG Step 1:
+    //   Create a new document with a viewBox and style. The style is
+    //   specified so that the SVG element will scale (down) in the browser.
+    //   With a physical size, the width and height attributes make laser
+    //   software import the SVG at that size.
+    let document = Document::new().set("viewBox", (0, 0, width, height));
+    let document = match config.physical_size() {
+        Some((physical_width, physical_height)) => document
+            .set("width", format!("{}{}", physical_width, config.units))
+            .set("height", format!("{}{}", physical_height, config.units))
+            .set("style", "max-width: 100%; height: auto;"),
+        None => document.set("style", format!("width: {}; max-width: 100%;", width)),
+    };
+
+    // Create the SVG Step 2:
+    //   Add a path per layer, grouping them when there is more than one.
+    match geometry.layers.as_slice() {
+        [layer] if layer.stroke.is_none() => document.add(layer_path(0, layer, config)),
+        layers => layer_groups(document, layers, config),
+    }
+}
+
+/// Add each layer to a document in its own Inkscape layer group.
+fn layer_groups(document: Document, layers: &[Layer], config: &SinusoidShadingConfig) -> Document {
+    let document = document.set(
+        "xmlns:inkscape",
+        "http://www.inkscape.org/namespaces/inkscape",
+    );
+
+    layers
+        .iter()
+        .enumerate()
+        .fold(document, |document, (i, layer)| {
+            let group = Group::new()
+                .set("id", layer.name.as_str())
+                .set("inkscape:groupmode", "layer")
+                .set("inkscape:label", layer.name.as_str());
+            document.add(group.add(layer_path(i, layer, config)))
+        })
+}
+
+/// Create the path for one layer.
+///
+/// The path uses the specified styles, with the data from `subpath_data`.
+/// Its colour is the `index`th of `config.layer_strokes`, if there is one.
+fn layer_path(index: usize, layer: &Layer, config: &SinusoidShadingConfig) -> Path {
+    let stroke = config
+        .layer_strokes
+        .get(index)
+        .or(layer.stroke.as_ref())
+        .unwrap_or(&config.stroke)
+        .as_str();
+    let fill = if layer.filled { stroke } else { "none" };
+    let mut path = Path::new()
+        .set("fill", fill)
+        .set("stroke", stroke)
+        .set("stroke-width", config.stroke_width.to_drawing_units(config));
+    if config.stroke_opacity != 1. {
+        path = path.set("stroke-opacity", config.stroke_opacity);
+        if layer.filled {
+            path = path.set("fill-opacity", config.stroke_opacity);
+        }
+    }
+    let dot_radius = config.dot_size.to_drawing_units(config) / 2.;
+    path.set("d", subpath_data(&layer.subpaths, dot_radius))
+}
+
+/// Convert subpaths into SVG path data.
+///
+/// Each subpath starts with a move to its start, so they aren't joined
+/// together. Runs of straight lines are written as a single line command, and
+/// the first run repeats the start point, as the path data always has. Points
+/// are written as circles of `dot_radius`, made of two arcs.
+fn subpath_data(subpaths: &[Subpath], dot_radius: f32) -> Data {
+    let mut data = Data::new();
+    for subpath in subpaths {
+        let start = subpath.start.position();
+        if subpath.is_point() {
+            let r = dot_radius;
+            data = data
+                .move_to((start.0 - r, start.1))
+                .elliptical_arc_by((r, r, 0, 1, 0, 2. * r, 0))
+                .elliptical_arc_by((r, r, 0, 1, 0, -2. * r, 0))
+                .close();
+            continue;
+        }
+
+        data = data.move_to(start);
+
+        let mut points = vec![start.0, start.1];
+        for segment in &subpath.segments {
+            match *segment {
+                Segment::Line(to) => points.extend([to.x, to.y]),
+                Segment::Cubic {
+                    control1,
+                    control2,
+                    to,
+                } => {
+                    if !points.is_empty() {
+                        let points = std::mem::take(&mut points);
+                        data = data.add(Command::Line(Position::Absolute, points.into()));
+                    }
+
+                    let parameters =
+                        vec![control1.0, control1.1, control2.0, control2.1, to.x, to.y];
+                    data = data.add(Command::CubicCurve(Position::Absolute, parameters.into()));
+                }
+            }
+        }
+
+        if !points.is_empty() {
+            data = data.add(Command::Line(Position::Absolute, points.into()));
+        }
+        if subpath.closed {
+            data = data.close();
+        }
+    }
+
+    data
+}
@@ -0,0 +1,31 @@
+// Rows of an image can be processed independently. Natively they are spread
+// across threads with rayon. WebAssembly in the browser has no threads, so
+// there they are processed one after another.
+
+/// Compute `f(0)`, `f(1)`, ..., `f(n - 1)`, in parallel where possible.
+///
+/// # Returns
+/// * The results, in order.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn map_range<T, F>(n: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync + Send,
+{
+    use rayon::prelude::*;
+
+    (0..n).into_par_iter().map(f).collect()
+}
+
+/// Compute `f(0)`, `f(1)`, ..., `f(n - 1)`, in parallel where possible.
+///
+/// # Returns
+/// * The results, in order.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn map_range<T, F>(n: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync + Send,
+{
+    (0..n).map(f).collect()
+}
@@ -0,0 +1,63 @@
+// Built-in presets for laser cutter stroke conventions.
+//
+// Laser software decides what to do with a vector from its stroke. Epilog and
+// Universal drivers only vector cut or score hairlines, and look up the power
+// for each stroke colour in a colour map. LightBurn puts each colour on its
+// own layer, and the Glowforge app makes a step for each colour. These
+// presets only set the stroke and layer colours, so they combine with any
+// other settings.
+
+/// Name and text (see `SinusoidShadingConfig::apply_preset`) of every built-in
+/// preset
+pub const BUILTIN_PRESETS: &[(&str, &str)] = &[
+    (
+        "default",
+        "# Plain 1 unit black lines
+stroke = black
+stroke_width = 1
+stroke_opacity = 1
+layer_strokes = none
+",
+    ),
+    (
+        "hairline",
+        "# Epilog and Universal hairline. Each layer (the sinusoids, then each
+# crosshatch layer or pen) gets the next colour of the driver's colour map,
+# so set the power for red, green, blue, yellow, magenta and cyan there.
+stroke = #ff0000
+stroke_width = 0.001in
+stroke_opacity = 1
+layer_strokes = #ff0000,#00ff00,#0000ff,#ffff00,#ff00ff,#00ffff
+",
+    ),
+    (
+        "lightburn",
+        "# LightBurn layer C01 (blue). Layer C00 (black) is usually the default
+# for everything else.
+stroke = #0000ff
+stroke_width = 1
+stroke_opacity = 1
+layer_strokes = none
+",
+    ),
+    (
+        "glowforge",
+        "# Blue is the usual Glowforge score colour. Set its step to Score.
+stroke = #0000ff
+stroke_width = 0.001in
+stroke_opacity = 1
+layer_strokes = none
+",
+    ),
+];
+
+/// Find a built-in preset by name.
+///
+/// # Returns
+/// * The preset text, ready for `SinusoidShadingConfig::apply_preset`.
+pub fn builtin_preset(name: &str) -> Option<&'static str> {
+    BUILTIN_PRESETS
+        .iter()
+        .find(|(preset_name, _)| *preset_name == name)
+        .map(|(_, preset)| *preset)
+}
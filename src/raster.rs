@@ -0,0 +1,165 @@
+use image::{DynamicImage, GrayImage, Luma};
+
+use crate::method::shade;
+use crate::SinusoidShadingConfig;
+
+/// Render the line shaded image as a grayscale bitmap.
+///
+/// This draws the same sinusoids as `process_image`, but into pixels instead
+/// of an SVG. It is meant for quick previews, so the lines are simply
+/// anti-aliased 1 unit wide strokes.
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration struct.
+/// * `scale` - Output pixels per SVG unit. The bitmap will be
+///   `config.width * scale` by `config.height * scale` pixels.
+///
+/// # Returns
+/// * A grayscale image with black lines on a white background.
+pub fn render_preview(img: &DynamicImage, config: &SinusoidShadingConfig, scale: f32) -> GrayImage {
+    let width = (config.width as f32 * scale).round().max(1.) as u32;
+    let height = (config.height as f32 * scale).round().max(1.) as u32;
+
+    let dot_radius = config.dot_size.to_drawing_units(config) / 2.;
+
+    let mut bitmap = Bitmap::new(width, height);
+    for layer in shade(img, config).layers {
+        for subpath in &layer.subpaths {
+            let line = subpath.outline(dot_radius);
+            if layer.filled || subpath.is_point() {
+                bitmap.fill_polygon(&line, scale);
+            }
+            bitmap.draw_polyline(&line, scale, scale);
+        }
+    }
+
+    bitmap.into_image()
+}
+
+/// Coverage buffer that polylines are drawn into.
+pub(crate) struct Bitmap {
+    width: u32,
+    height: u32,
+    coverage: Vec<f32>,
+}
+
+impl Bitmap {
+    /// Create an empty (white) bitmap.
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Bitmap {
+            width,
+            height,
+            coverage: vec![0.; (width * height) as usize],
+        }
+    }
+
+    /// Draw a polyline with the given stroke width.
+    ///
+    /// Points are multiplied by `scale` to convert them to pixels and the
+    /// stroke width is given in pixels.
+    pub(crate) fn draw_polyline(&mut self, points: &[(f32, f32)], scale: f32, stroke: f32) {
+        let scaled = |&(x, y): &(f32, f32)| (x * scale, y * scale);
+
+        for pair in points.windows(2) {
+            self.draw_segment(scaled(&pair[0]), scaled(&pair[1]), stroke);
+        }
+    }
+
+    /// Fill a closed polygon, e.g. a halftone dot.
+    ///
+    /// Pixels are filled when their centre is inside the polygon (by the even
+    /// odd rule). Points are multiplied by `scale` to convert them to pixels.
+    /// There is no anti-aliasing, so the outline is usually drawn over it too.
+    pub(crate) fn fill_polygon(&mut self, points: &[(f32, f32)], scale: f32) {
+        let points: Vec<_> = points
+            .iter()
+            .map(|&(x, y)| (x * scale, y * scale))
+            .collect();
+        let Some(first) = points.first() else {
+            return;
+        };
+
+        let (y_min, y_max) = points.iter().fold((first.1, first.1), |(lo, hi), &(_, y)| {
+            (lo.min(y), hi.max(y))
+        });
+        let y0 = y_min.floor().max(0.) as u32;
+        let y1 = (y_max.ceil().max(0.) as u32).min(self.height);
+
+        let mut crossings = Vec::new();
+        for py in y0..y1 {
+            // Where each edge (including the closing one) crosses the row of
+            // pixel centres
+            let y = py as f32 + 0.5;
+            crossings.clear();
+            for (i, &(ax, ay)) in points.iter().enumerate() {
+                let (bx, by) = points[(i + 1) % points.len()];
+                if (ay <= y) != (by <= y) {
+                    crossings.push(ax + (y - ay) / (by - ay) * (bx - ax));
+                }
+            }
+            crossings.sort_by(f32::total_cmp);
+
+            for span in crossings.chunks_exact(2) {
+                let x0 = (span[0] - 0.5).ceil().max(0.) as u32;
+                let x1 = ((span[1] - 0.5).floor() + 1.).clamp(0., self.width as f32) as u32;
+                for px in x0..x1 {
+                    self.coverage[(py * self.width + px) as usize] = 1.;
+                }
+            }
+        }
+    }
+
+    /// Draw a single line segment.
+    ///
+    /// Every pixel near the segment is darkened based on its distance from the
+    /// segment, which gives cheap anti-aliasing. Overlapping segments keep the
+    /// darkest coverage rather than adding up, so the many short segments of a
+    /// sinusoid don't produce dark spots where they meet.
+    fn draw_segment(&mut self, a: (f32, f32), b: (f32, f32), stroke: f32) {
+        let half = stroke / 2. + 0.5;
+
+        let x0 = (a.0.min(b.0) - half).floor().max(0.) as u32;
+        let x1 = (a.0.max(b.0) + half).ceil().min(self.width as f32) as u32;
+        let y0 = (a.1.min(b.1) - half).floor().max(0.) as u32;
+        let y1 = (a.1.max(b.1) + half).ceil().min(self.height as f32) as u32;
+
+        for py in y0..y1 {
+            for px in x0..x1 {
+                // Distance from the pixel center to the segment
+                let p = (px as f32 + 0.5, py as f32 + 0.5);
+                let d = distance_to_segment(p, a, b);
+
+                let c = (half - d).clamp(0., 1.);
+                let i = (py * self.width + px) as usize;
+                self.coverage[i] = self.coverage[i].max(c);
+            }
+        }
+    }
+
+    /// Convert the coverage into a black on white grayscale image.
+    pub(crate) fn into_image(self) -> GrayImage {
+        let mut out = GrayImage::new(self.width, self.height);
+        for (pixel, c) in out.pixels_mut().zip(self.coverage) {
+            *pixel = Luma([(255. * (1. - c)).round() as u8]);
+        }
+
+        out
+    }
+}
+
+/// Shortest distance from point `p` to the segment `a`-`b`.
+fn distance_to_segment(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len2 = dx * dx + dy * dy;
+
+    // Position of the closest point along the segment, from 0 (a) to 1 (b)
+    let t = if len2 > 0. {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len2).clamp(0., 1.)
+    } else {
+        0.
+    };
+
+    let (cx, cy) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()
+}
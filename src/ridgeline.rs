@@ -0,0 +1,222 @@
+use image::{DynamicImage, GrayImage};
+
+use ndarray::ArrayView1;
+
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::sinusoid::{average_rows, darkness, Tracker};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Draw a grayscale image as rows raised by its darkness, like a ridgeline
+/// plot, with the parts hidden behind nearer rows removed.
+///
+/// # Returns
+/// * A single layer of ridgelines, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_ridgelines(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let luma = img.to_luma8();
+    let tracker = Tracker::new(progress, cancel, ridge_count(&luma, config));
+
+    let lines = ridgelines(&luma, config, &tracker).ok_or(ImageProcessError::Cancelled)?;
+    Ok(vec![Layer::from_subpaths("ridgeline", lines)])
+}
+
+/// Number of rows drawn for an image, as `average_rows` makes.
+pub(crate) fn ridge_count(luma: &GrayImage, config: &SinusoidShadingConfig) -> usize {
+    config.line_count().min(luma.height() as usize)
+}
+
+/// Raise every row of averages by its darkness and remove hidden lines.
+///
+/// Each row rests on the bottom of its band, and is raised by up to
+/// `2 * config.amplitude` rows where the image is black. So an amplitude of
+/// 0.5 just reaches the row above, as with the sinusoids, and larger ones
+/// make the rows overlap.
+///
+/// Rows are drawn from the bottom up, as if the nearest row were at the
+/// bottom. The highest point drawn so far at each column (the horizon) is
+/// kept, and only the parts of a row above it are visible. Where a row
+/// crosses the horizon, it is cut at the crossing.
+///
+/// # Arguments
+/// * `luma` - The grayscale image.
+/// * `config` - The configuration struct.
+/// * `tracker` - Each row is reported as a line.
+///
+/// # Returns
+/// * The visible parts of the rows, or `None` if it was cancelled.
+pub(crate) fn ridgelines(
+    luma: &GrayImage,
+    config: &SinusoidShadingConfig,
+    tracker: &Tracker,
+) -> Option<Vec<Subpath>> {
+    let avgs = average_rows(luma, config);
+    let row_height = config.height as f32 / avgs.nrows() as f32;
+    let rise = 2. * config.amplitude * row_height;
+
+    // One point per pixel column, at its centre
+    let column_width = config.width as f32 / avgs.ncols() as f32;
+    let xs: Vec<f32> = (0..avgs.ncols())
+        .map(|i| (i as f32 + 0.5) * column_width)
+        .collect();
+
+    // Nothing is hidden below the bottom of the output
+    let mut horizon = vec![f32::INFINITY; xs.len()];
+    let mut visible = Vec::new();
+
+    for yi in (0..avgs.nrows()).rev() {
+        if tracker.cancelled() {
+            return None;
+        }
+
+        let points = raise(avgs.row(yi), &xs, (yi + 1) as f32 * row_height, rise);
+        visible.extend(clip_to_horizon(&points, &horizon));
+        for (height, point) in horizon.iter_mut().zip(&points) {
+            *height = height.min(point.y);
+        }
+
+        tracker.line_done();
+    }
+
+    Some(visible)
+}
+
+/// A row of averages as points raised above its baseline. Rows that rise
+/// past the top of the output are flattened against it.
+fn raise(row: ArrayView1<u8>, xs: &[f32], baseline: f32, rise: f32) -> Vec<Vertex> {
+    row.iter()
+        .zip(xs)
+        .map(|(&value, &x)| {
+            let dark = darkness(value);
+            Vertex::with_intensity(x, (baseline - rise * dark).max(0.), dark)
+        })
+        .collect()
+}
+
+/// The parts of a row above the horizon (with smaller y, since y points
+/// down).
+///
+/// # Arguments
+/// * `points` - The row, one point per column.
+/// * `horizon` - Highest y drawn so far at each column.
+fn clip_to_horizon(points: &[Vertex], horizon: &[f32]) -> Vec<Subpath> {
+    // How far above the horizon each point is. Points exactly on it, e.g.
+    // flat rows against the top of the output, are hidden.
+    let above: Vec<f32> = points
+        .iter()
+        .zip(horizon)
+        .map(|(point, &height)| height - point.y)
+        .collect();
+
+    let mut parts = Vec::new();
+    let mut run = Vec::new();
+    for i in 0..points.len() {
+        if i > 0 && (above[i - 1] > 0.) != (above[i] > 0.) {
+            // Where the row crosses the horizon between the two points. The
+            // horizon is infinite below the first row, which never crosses it.
+            let t = above[i - 1] / (above[i - 1] - above[i]);
+            run.push(lerp(points[i - 1], points[i], t));
+            if above[i] <= 0. {
+                parts.extend(Subpath::polyline(run.drain(..)));
+            }
+        }
+
+        if above[i] > 0. {
+            run.push(points[i]);
+        }
+    }
+    parts.extend(Subpath::polyline(run));
+
+    parts
+}
+
+/// The vertex `t` (0 to 1) of the way from `a` to `b`.
+fn lerp(a: Vertex, b: Vertex, t: f32) -> Vertex {
+    let mix = |p: f32, q: f32| p + (q - p) * t;
+    Vertex {
+        x: mix(a.x, b.x),
+        y: mix(a.y, b.y),
+        intensity: a.intensity.zip(b.intensity).map(|(p, q)| mix(p, q)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    fn row(ys: &[f32]) -> Vec<Vertex> {
+        ys.iter()
+            .enumerate()
+            .map(|(x, &y)| Vertex::new(x as f32, y))
+            .collect()
+    }
+
+    fn positions(parts: &[Subpath]) -> Vec<Vec<(f32, f32)>> {
+        parts
+            .iter()
+            .map(|part| part.vertices().map(|v| v.position()).collect())
+            .collect()
+    }
+
+    fn trace(luma: &GrayImage, config: &SinusoidShadingConfig) -> Vec<Subpath> {
+        let cancel = CancellationToken::new();
+        let tracker = Tracker::new(&|_, _| {}, &cancel, ridge_count(luma, config));
+        ridgelines(luma, config, &tracker).unwrap()
+    }
+
+    #[test]
+    fn cut_where_row_goes_behind_horizon() {
+        let parts = clip_to_horizon(&row(&[5., 5., 5., 5.]), &[10., 10., 0., 0.]);
+        assert_eq!(positions(&parts), vec![vec![(0., 5.), (1., 5.), (1.5, 5.)]]);
+    }
+
+    #[test]
+    fn split_where_row_dips_behind_horizon() {
+        let parts = clip_to_horizon(&row(&[5., 5., 5.]), &[10., 0., 10.]);
+        assert_eq!(
+            positions(&parts),
+            vec![vec![(0., 5.), (0.5, 5.)], vec![(1.5, 5.), (2., 5.)]]
+        );
+    }
+
+    #[test]
+    fn white_rows_are_flat_and_visible() {
+        let config = SinusoidShadingConfig {
+            width: 40,
+            height: 40,
+            lines: 4,
+            ..Default::default()
+        };
+        let luma = GrayImage::from_pixel(40, 40, Luma([255]));
+
+        let parts = trace(&luma, &config);
+        let baselines: Vec<f32> = parts.iter().map(|part| part.start.y).collect();
+        assert_eq!(baselines, vec![40., 30., 20., 10.]);
+        for part in &parts {
+            assert!(part.vertices().all(|v| v.y == part.start.y));
+            assert_eq!(part.vertices().count(), 40);
+        }
+    }
+
+    #[test]
+    fn tall_near_row_hides_rows_behind_it() {
+        // The bottom row rises three rows, to the baseline of the top row
+        let config = SinusoidShadingConfig {
+            width: 40,
+            height: 40,
+            lines: 4,
+            amplitude: 1.5,
+            ..Default::default()
+        };
+        let luma = GrayImage::from_fn(40, 40, |_, y| Luma([if y >= 30 { 0 } else { 255 }]));
+
+        let parts = trace(&luma, &config);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].vertices().all(|v| (v.y - 10.).abs() < 1e-4));
+    }
+}
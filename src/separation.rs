@@ -0,0 +1,424 @@
+use clap::ValueEnum;
+
+use image::{DynamicImage, GrayImage, RgbImage};
+
+use crate::contour::Contours;
+use crate::crosshatch::{Crosshatch, HatchLayer};
+use crate::curve::SpaceFilling;
+use crate::flow::Flow;
+use crate::geometry::Layer;
+use crate::halftone::Halftone;
+use crate::ridgeline::{ridge_count, ridgelines};
+use crate::sinusoid::{average_rows, FrequencyScale, Tracker};
+use crate::stipple::{dot_layer, Density};
+use crate::tsp::{tour, tour_line_count};
+use crate::{CancellationToken, ImageProcessError, Method, SinusoidShadingConfig};
+
+/// How to split a colour image between pens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Separation {
+    /// A single pen, shading the grayscale image
+    #[default]
+    None,
+
+    /// Cyan, magenta, yellow and black pens on white paper
+    Cmyk,
+
+    /// Red, green and blue pens on black paper. Each pen follows the
+    /// brightness of its channel.
+    Rgb,
+}
+
+value_enum_strings!(Separation);
+
+/// One pen of a separation
+struct Pen {
+    /// Layer name
+    name: &'static str,
+
+    /// Stroke colour
+    colour: &'static str,
+
+    /// Direction of the pen's sinusoids in degrees. Every pen gets its own
+    /// angle, as with halftone screens, so that the layers don't form moiré
+    /// patterns.
+    angle: f32,
+}
+
+/// The usual CMYK screen angles, with yellow (the least visible) closest to
+/// the others.
+const CMYK_PENS: &[Pen] = &[
+    Pen {
+        name: "cyan",
+        colour: "#00ffff",
+        angle: 15.,
+    },
+    Pen {
+        name: "magenta",
+        colour: "#ff00ff",
+        angle: 75.,
+    },
+    Pen {
+        name: "yellow",
+        colour: "#ffff00",
+        angle: 0.,
+    },
+    Pen {
+        name: "black",
+        colour: "#000000",
+        angle: 45.,
+    },
+];
+
+const RGB_PENS: &[Pen] = &[
+    Pen {
+        name: "red",
+        colour: "#ff0000",
+        angle: 15.,
+    },
+    Pen {
+        name: "green",
+        colour: "#00ff00",
+        angle: 75.,
+    },
+    Pen {
+        name: "blue",
+        colour: "#0000ff",
+        angle: 45.,
+    },
+];
+
+impl Separation {
+    /// The pens to draw with. Empty for `Separation::None`.
+    fn pens(self) -> &'static [Pen] {
+        match self {
+            Separation::None => &[],
+            Separation::Cmyk => CMYK_PENS,
+            Separation::Rgb => RGB_PENS,
+        }
+    }
+
+    /// Split an image into one grayscale image per pen, where black means as
+    /// much ink as possible.
+    fn channels(self, rgb: &RgbImage) -> Vec<GrayImage> {
+        let (width, height) = rgb.dimensions();
+        let mut channels = vec![GrayImage::new(width, height); self.pens().len()];
+
+        for (x, y, pixel) in rgb.enumerate_pixels() {
+            let [r, g, b] = pixel.0.map(|v| f32::from(v) / 255.);
+
+            // Amount of ink for each pen, from 0 to 1. Pens past the end of
+            // `pens()` are ignored.
+            let ink = match self {
+                Separation::None => [0.; 4],
+                Separation::Cmyk => {
+                    // Black takes as much as it can, the colours make up the
+                    // rest
+                    let k = 1. - r.max(g).max(b);
+                    if k < 1. {
+                        let c = (1. - r - k) / (1. - k);
+                        let m = (1. - g - k) / (1. - k);
+                        let y = (1. - b - k) / (1. - k);
+                        [c, m, y, k]
+                    } else {
+                        [0., 0., 0., 1.]
+                    }
+                }
+                Separation::Rgb => [r, g, b, 0.],
+            };
+
+            for (channel, ink) in channels.iter_mut().zip(ink) {
+                channel.put_pixel(x, y, [(255. * (1. - ink)).round() as u8].into());
+            }
+        }
+
+        channels
+    }
+}
+
+/// Trace one layer per pen, see `SinusoidShadingConfig::separation`.
+///
+/// Each pen is shaded like a grayscale image at its own angle. Crosshatch
+/// layers are added to every pen, turned by the pen's angle.
+///
+/// # Arguments
+/// * `img` - The colour image.
+/// * `config` - The configuration struct.
+/// * `method` - How to shade each pen.
+/// * `progress` - Called with the number of rows done and the total.
+/// * `cancel` - Checked before each row is started.
+///
+/// # Returns
+/// * One layer per pen, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_pens(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    method: Method,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let pens = config.separation.pens();
+    let channels = config.separation.channels(&img.to_rgb8());
+
+    let layers = match method {
+        Method::Sinusoid => pen_sinusoids(pens, &channels, config, progress, cancel),
+        Method::Halftone => pen_halftones(pens, &channels, config, progress, cancel),
+        Method::Stipple => pen_stipples(pens, &channels, config, progress, cancel, false),
+        Method::Tsp => pen_stipples(pens, &channels, config, progress, cancel, true),
+        Method::Flow => pen_flows(pens, &channels, config, progress, cancel),
+        Method::Ridgeline => pen_ridgelines(pens, &channels, config, progress, cancel),
+        Method::Contour => pen_contours(pens, &channels, config, progress, cancel),
+        Method::Curve => pen_curves(pens, &channels, config, progress, cancel),
+    };
+
+    layers.ok_or(ImageProcessError::Cancelled)
+}
+
+/// The frequency scale for sinusoids over every pen's channel. It is shared,
+/// so the same amount of ink gives the same frequency for every pen.
+fn shared_scale(channels: &[GrayImage], config: &SinusoidShadingConfig) -> FrequencyScale {
+    let avgs: Vec<_> = channels.iter().map(|c| average_rows(c, config)).collect();
+    let lightest = avgs.iter().flat_map(|a| a.iter().copied()).max();
+    let darkest = avgs.iter().flat_map(|a| a.iter().copied()).min();
+    FrequencyScale::from_range(lightest, darkest, config)
+}
+
+/// Shade every pen with sinusoids.
+fn pen_sinusoids(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Option<Vec<Layer>> {
+    let scale = shared_scale(channels, config);
+
+    let hatches: Vec<_> = channels
+        .iter()
+        .map(|channel| Crosshatch::new(channel, &scale, config))
+        .collect();
+
+    // The pen's own sinusoids are drawn everywhere, like the horizontal ones
+    // without separation
+    let pen_layers = |pen: &Pen| {
+        let base = HatchLayer {
+            angle: pen.angle,
+            threshold: 0.,
+        };
+        let extra = config.crosshatch.iter().map(|layer| HatchLayer {
+            angle: layer.angle + pen.angle,
+            threshold: layer.threshold,
+        });
+        std::iter::once(base).chain(extra).collect::<Vec<_>>()
+    };
+
+    let total = pens
+        .iter()
+        .zip(&hatches)
+        .flat_map(|(pen, hatch)| pen_layers(pen).into_iter().map(|l| hatch.line_count(&l)))
+        .sum();
+    let tracker = Tracker::new(progress, cancel, total);
+
+    pens.iter()
+        .zip(&hatches)
+        .map(|(pen, hatch)| {
+            let mut lines = Vec::new();
+            for layer in pen_layers(pen) {
+                lines.extend(hatch.trace(&layer, &tracker)?);
+            }
+
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                ..Layer::from_subpaths(pen.name, lines)
+            })
+        })
+        .collect()
+}
+
+/// Shade every pen with sinusoids along the streamlines of its own channel.
+fn pen_flows(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Option<Vec<Layer>> {
+    let scale = shared_scale(channels, config);
+
+    let flows: Vec<_> = channels
+        .iter()
+        .map(|channel| Flow::new(channel, &scale, config))
+        .collect();
+
+    let total = flows.iter().map(Flow::seed_rows).sum();
+    let tracker = Tracker::new(progress, cancel, total);
+
+    pens.iter()
+        .zip(&flows)
+        .map(|(pen, flow)| {
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                ..Layer::from_subpaths(pen.name, flow.trace(&tracker)?)
+            })
+        })
+        .collect()
+}
+
+/// Draw every pen's ridgelines. Rows are always horizontal, so the pens'
+/// angles aren't used.
+fn pen_ridgelines(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Option<Vec<Layer>> {
+    let total = channels.iter().map(|c| ridge_count(c, config)).sum();
+    let tracker = Tracker::new(progress, cancel, total);
+
+    pens.iter()
+        .zip(channels)
+        .map(|(pen, channel)| {
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                ..Layer::from_subpaths(pen.name, ridgelines(channel, config, &tracker)?)
+            })
+        })
+        .collect()
+}
+
+/// Trace every pen's contours, with its hatching in the same layer.
+/// Hatching is always horizontal, so the pens' angles aren't used.
+fn pen_contours(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Option<Vec<Layer>> {
+    let contours: Vec<_> = channels
+        .iter()
+        .map(|channel| Contours::new(channel, config))
+        .collect();
+
+    let total = contours.iter().map(Contours::line_count).sum();
+    let tracker = Tracker::new(progress, cancel, total);
+
+    pens.iter()
+        .zip(&contours)
+        .map(|(pen, contours)| {
+            let subpaths = contours.layers(&tracker)?.concat();
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                ..Layer::from_subpaths(pen.name, subpaths)
+            })
+        })
+        .collect()
+}
+
+/// Draw one space-filling curve per pen. The curves always start in the
+/// same corner, so the pens' angles aren't used.
+fn pen_curves(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Option<Vec<Layer>> {
+    let scale = shared_scale(channels, config);
+
+    let tracker = Tracker::new(progress, cancel, pens.len());
+
+    pens.iter()
+        .zip(channels)
+        .map(|(pen, channel)| {
+            let curve = SpaceFilling::new(channel, &scale, config).trace(&tracker)?;
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                ..Layer::from_subpaths(pen.name, curve)
+            })
+        })
+        .collect()
+}
+
+/// Shade every pen with a halftone screen at the pen's angle.
+fn pen_halftones(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Option<Vec<Layer>> {
+    let screens: Vec<_> = channels
+        .iter()
+        .map(|channel| Halftone::new(channel, config))
+        .collect();
+
+    let total = pens
+        .iter()
+        .zip(&screens)
+        .map(|(pen, screen)| screen.row_count(pen.angle))
+        .sum();
+    let tracker = Tracker::new(progress, cancel, total);
+
+    pens.iter()
+        .zip(&screens)
+        .map(|(pen, screen)| {
+            let dots = screen.trace(pen.angle, &tracker)?;
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                filled: true,
+                ..Layer::from_subpaths(pen.name, dots)
+            })
+        })
+        .collect()
+}
+
+/// Stipple every pen. The pen with the most ink gets `config.stipple_points`
+/// dots and the others get fewer, in proportion to their ink. With `tours`,
+/// each pen's dots are joined into a TSP tour.
+fn pen_stipples(
+    pens: &[Pen],
+    channels: &[GrayImage],
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+    tours: bool,
+) -> Option<Vec<Layer>> {
+    let densities: Vec<_> = channels
+        .iter()
+        .map(|channel| Density::new(channel, config, config.stipple_points))
+        .collect();
+    let most_ink = densities.iter().map(Density::total).fold(0., f32::max);
+
+    let lines = if tours {
+        tour_line_count(config)
+    } else {
+        config.stipple_iterations
+    };
+    let tracker = Tracker::new(progress, cancel, pens.len() * lines);
+
+    pens.iter()
+        .zip(&densities)
+        .map(|(pen, density)| {
+            let share = if most_ink > 0. {
+                density.total() / most_ink
+            } else {
+                0.
+            };
+            let count = (config.stipple_points as f32 * share).round() as usize;
+
+            let points = density.stipple(count, config.stipple_iterations, &tracker)?;
+            let layer = if tours {
+                Layer::from_subpaths(pen.name, tour(points, config, &tracker)?)
+            } else {
+                dot_layer(pen.name, points)
+            };
+
+            Some(Layer {
+                stroke: Some(pen.colour.to_string()),
+                ..layer
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,236 @@
+use std::str::FromStr;
+
+use image::{DynamicImage, GrayImage};
+
+use svg::Document;
+
+use crate::font;
+use crate::geometry::{Geometry, Layer};
+use crate::method::shade;
+use crate::output::{OutputBackend, SvgBackend};
+use crate::raster::Bitmap;
+use crate::{Length, SinusoidShadingConfig};
+
+/// Name of the layer of cell labels
+const LABELS: &str = "labels";
+
+/// One axis of a parameter sweep: a configuration field and the values to try.
+///
+/// Parses from `field=value1,value2,...`, e.g. `lines=32,64,96`.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub field: String,
+    pub values: Vec<String>,
+}
+
+impl FromStr for Sweep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, values) = s
+            .split_once('=')
+            .ok_or_else(|| "expected `field=value1,value2,...`".to_string())?;
+
+        let field = field.trim();
+        let values: Vec<String> = values
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.is_empty() {
+            return Err("no values given".to_string());
+        }
+        for value in &values {
+            SinusoidShadingConfig::check_field(field, value)?;
+        }
+
+        Ok(Sweep {
+            field: field.to_string(),
+            values,
+        })
+    }
+}
+
+/// The laid out contact sheet, shared by the SVG and bitmap outputs.
+struct Layout {
+    /// A layer for each layer of every cell, followed by the labels
+    geometry: Geometry,
+
+    /// The size of the sheet, with 1 unit wide black lines
+    config: SinusoidShadingConfig,
+
+    label_size: f32,
+}
+
+/// Render the same image with different settings, laid out as a grid.
+///
+/// Each column uses one value from `x` and each row one value from `y`. Every
+/// cell is labelled with the values it was rendered with.
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration shared by every cell.
+/// * `x` - The field varied across columns.
+/// * `y` - The field varied across rows, if any.
+///
+/// # Returns
+/// * An SVG document containing the whole grid.
+pub fn contact_sheet(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    x: &Sweep,
+    y: Option<&Sweep>,
+) -> Document {
+    let layout = layout(img, config, x, y);
+    SvgBackend.write(&layout.geometry, &layout.config)
+}
+
+/// Render a contact sheet (see `contact_sheet`) as a grayscale bitmap.
+///
+/// # Arguments
+/// * `img` - A reference to the image.
+/// * `config` - The configuration shared by every cell.
+/// * `x` - The field varied across columns.
+/// * `y` - The field varied across rows, if any.
+/// * `scale` - Output pixels per SVG unit.
+///
+/// # Returns
+/// * A grayscale image with black lines on a white background.
+pub fn contact_sheet_preview(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    x: &Sweep,
+    y: Option<&Sweep>,
+    scale: f32,
+) -> GrayImage {
+    let layout = layout(img, config, x, y);
+
+    let width = (layout.config.width as f32 * scale).round().max(1.) as u32;
+    let height = (layout.config.height as f32 * scale).round().max(1.) as u32;
+
+    let mut bitmap = Bitmap::new(width, height);
+    for layer in &layout.geometry.layers {
+        let stroke = if layer.name == LABELS {
+            font::stroke_width(layout.label_size) * scale
+        } else {
+            scale
+        };
+
+        // Cells are already outlined, so there are no points left to draw
+        for subpath in &layer.subpaths {
+            let line = subpath.flatten();
+            if layer.filled {
+                bitmap.fill_polygon(&line, scale);
+            }
+            bitmap.draw_polyline(&line, scale, stroke);
+        }
+    }
+
+    bitmap.into_image()
+}
+
+/// Shade every cell and place it in the grid.
+fn layout(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    x: &Sweep,
+    y: Option<&Sweep>,
+) -> Layout {
+    // Without a `y` sweep there is a single row using `config` as-is
+    let y_values: Vec<Option<&String>> = match y {
+        Some(y) => y.values.iter().map(Some).collect(),
+        None => vec![None],
+    };
+
+    // Configuration and label of every cell, row by row
+    let cells: Vec<Vec<(SinusoidShadingConfig, String)>> = y_values
+        .iter()
+        .map(|y_value| {
+            x.values
+                .iter()
+                .map(|x_value| {
+                    let mut cell = config.clone();
+                    cell.set_field(&x.field, x_value);
+                    let mut label = format!("{}={}", x.field, cell.get_field(&x.field));
+
+                    if let (Some(y), Some(y_value)) = (y, y_value) {
+                        cell.set_field(&y.field, y_value);
+                        label += &format!(" {}={}", y.field, cell.get_field(&y.field));
+                    }
+
+                    (cell, label)
+                })
+                .collect()
+        })
+        .collect();
+
+    // The sweep may change the output size, so leave room for the largest cell
+    let all_cells = || cells.iter().flatten().map(|(c, _)| c);
+    let cell_width = all_cells().map(|c| c.width).max().unwrap_or(0) as f32;
+    let cell_height = all_cells().map(|c| c.height).max().unwrap_or(0) as f32;
+
+    // Labels are sized relative to the cells, and go in a strip below each one
+    let label_size = (cell_height / 30.).max(4.);
+    let gap = label_size * 2.;
+    let pitch_x = cell_width + gap;
+    let pitch_y = cell_height + label_size + 2. * gap;
+
+    let mut layers = Vec::new();
+    let mut labels = Vec::new();
+
+    for (row, row_cells) in cells.iter().enumerate() {
+        for (col, (cell, label)) in row_cells.iter().enumerate() {
+            let left = gap + col as f32 * pitch_x;
+            let top = gap + row as f32 * pitch_y;
+
+            // Dots are outlined with the cell's own dot size, which the
+            // sheet can't set per cell
+            let dot_radius = cell.dot_size.to_drawing_units(cell) / 2.;
+            layers.extend(shade(img, cell).layers.into_iter().map(|layer| {
+                let lines = layer
+                    .subpaths
+                    .iter()
+                    .map(|subpath| {
+                        let line = subpath.outline(dot_radius);
+                        line.into_iter().map(|(x, y)| (x + left, y + top)).collect()
+                    })
+                    .collect();
+                Layer {
+                    stroke: layer.stroke,
+                    filled: layer.filled,
+                    ..Layer::new(
+                        format!("cell-{}-{}-{}", row + 1, col + 1, layer.name),
+                        lines,
+                    )
+                }
+            }));
+
+            // Shrink labels that don't fit under their cell
+            let size =
+                label_size.min(label_size * cell_width / font::text_width(label, label_size));
+            labels.extend(font::text_paths(
+                label,
+                left,
+                top + cell_height + gap / 2.,
+                size,
+            ));
+        }
+    }
+    layers.push(Layer::new(LABELS, labels));
+
+    let width = gap + x.values.len() as f32 * pitch_x;
+    let height = gap + y_values.len() as f32 * pitch_y - gap / 2.;
+    Layout {
+        geometry: Geometry { layers },
+        config: SinusoidShadingConfig {
+            width: width.ceil() as usize,
+            height: height.ceil() as usize,
+            stroke_width: Length {
+                value: 1.,
+                units: None,
+            },
+            ..Default::default()
+        },
+        label_size,
+    }
+}
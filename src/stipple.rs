@@ -0,0 +1,446 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage};
+
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::parallel;
+use crate::sinusoid::{darkness, gamma_table, Tracker};
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Density samples per dot. Larger images are shrunk to about this many
+/// pixels per dot, which bounds the time each round of relaxation takes.
+const SAMPLES_PER_POINT: usize = 64;
+
+/// Samples are summed in this many bands of rows, one per thread
+const BANDS: usize = 16;
+
+/// Attempts per dot when placing the first dots, before giving up on a very
+/// light image
+const SEED_ATTEMPTS: usize = 1000;
+
+/// Seed for placing the first dots, so an image always gives the same
+/// stipples
+const SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Stipple a grayscale image with `config.stipple_points` dots.
+///
+/// # Returns
+/// * A single layer of dots, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_stipples(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let density = Density::new(&img.to_luma8(), config, config.stipple_points);
+    let tracker = Tracker::new(progress, cancel, config.stipple_iterations);
+
+    let points = density
+        .stipple(config.stipple_points, config.stipple_iterations, &tracker)
+        .ok_or(ImageProcessError::Cancelled)?;
+    Ok(vec![dot_layer("stipple", points)])
+}
+
+/// A filled layer with a dot at each point.
+pub(crate) fn dot_layer(name: &str, points: Vec<Vertex>) -> Layer {
+    Layer {
+        filled: true,
+        ..Layer::from_subpaths(name, points.into_iter().map(Subpath::point).collect())
+    }
+}
+
+/// Darkness of an image, sampled on a grid over the output.
+///
+/// Dots are placed with Secord's weighted Voronoi stippling: scatter them
+/// with the darkness as probability, then repeatedly move each dot to the
+/// darkness weighted centroid of its Voronoi cell (Lloyd's relaxation). The
+/// cells are found by assigning every sample to its nearest dot.
+pub(crate) struct Density {
+    /// Number of samples across and down
+    width: usize,
+    height: usize,
+
+    /// Size of a sample in output units
+    sample_width: f32,
+    sample_height: f32,
+
+    /// Darkness of each sample, from 0 to 1, row by row
+    values: Vec<f32>,
+}
+
+impl Density {
+    /// Sample an image for stippling.
+    ///
+    /// # Arguments
+    /// * `luma` - The grayscale image.
+    /// * `config` - The configuration struct, for the output size and gamma.
+    /// * `points` - Number of dots that will be placed. The image is shrunk
+    ///   to `SAMPLES_PER_POINT` pixels per dot if it is larger.
+    pub(crate) fn new(luma: &GrayImage, config: &SinusoidShadingConfig, points: usize) -> Self {
+        let pixels = luma.width() as usize * luma.height() as usize;
+        let target = points.max(1) * SAMPLES_PER_POINT;
+
+        let resized;
+        let luma = if pixels > target {
+            let ratio = (target as f32 / pixels as f32).sqrt();
+            let width = ((luma.width() as f32 * ratio).round() as u32).max(1);
+            let height = ((luma.height() as f32 * ratio).round() as u32).max(1);
+            resized = imageops::resize(luma, width, height, FilterType::Triangle);
+            &resized
+        } else {
+            luma
+        };
+
+        let gamma = gamma_table(config.gamma);
+        let (width, height) = (luma.width() as usize, luma.height() as usize);
+
+        Density {
+            width,
+            height,
+            sample_width: config.width as f32 / width as f32,
+            sample_height: config.height as f32 / height as f32,
+            values: luma
+                .pixels()
+                .map(|p| darkness(gamma[p.0[0] as usize]))
+                .collect(),
+        }
+    }
+
+    /// Sum of the darkness of every sample, i.e. the amount of ink.
+    pub(crate) fn total(&self) -> f32 {
+        self.values.iter().sum()
+    }
+
+    /// Darkness at a point in output units.
+    pub(crate) fn at(&self, (x, y): (f32, f32)) -> f32 {
+        let col = ((x / self.sample_width) as usize).min(self.width - 1);
+        let row = ((y / self.sample_height) as usize).min(self.height - 1);
+        self.values[row * self.width + col]
+    }
+
+    /// Size of the output in output units.
+    fn size(&self) -> (f32, f32) {
+        (
+            self.width as f32 * self.sample_width,
+            self.height as f32 * self.sample_height,
+        )
+    }
+
+    /// Place and relax the dots.
+    ///
+    /// # Arguments
+    /// * `count` - Number of dots.
+    /// * `iterations` - Rounds of relaxation. Each one is reported to
+    ///   `tracker` as a line.
+    /// * `tracker` - Progress and cancellation.
+    ///
+    /// # Returns
+    /// * The dots, with the darkness under each one, in an order that keeps
+    ///   the moves between them short. `None` if it was cancelled.
+    pub(crate) fn stipple(
+        &self,
+        count: usize,
+        iterations: usize,
+        tracker: &Tracker,
+    ) -> Option<Vec<Vertex>> {
+        let mut points = self.seed(count);
+        for _ in 0..iterations {
+            if tracker.cancelled() {
+                return None;
+            }
+
+            if !points.is_empty() {
+                points = self.relax(&points);
+            }
+            tracker.line_done();
+        }
+
+        let spacing = self.spacing(points.len());
+        Some(
+            travel_order(points, spacing)
+                .into_iter()
+                .map(|(x, y)| Vertex::with_intensity(x, y, self.at((x, y))))
+                .collect(),
+        )
+    }
+
+    /// Scatter dots with the darkness as probability, by rejection sampling.
+    fn seed(&self, count: usize) -> Vec<(f32, f32)> {
+        let max = self.values.iter().copied().fold(0., f32::max);
+        if max <= 0. {
+            return Vec::new();
+        }
+
+        let (width, height) = self.size();
+        let mut rng = Rng(SEED);
+        let mut points = Vec::with_capacity(count);
+        for _ in 0..count * SEED_ATTEMPTS {
+            if points.len() == count {
+                break;
+            }
+
+            let point = (rng.next() * width, rng.next() * height);
+            if rng.next() * max < self.at(point) {
+                points.push(point);
+            }
+        }
+
+        points
+    }
+
+    /// One round of Lloyd's relaxation.
+    ///
+    /// # Returns
+    /// * The darkness weighted centroid of each dot's Voronoi cell. Dots
+    ///   whose cell is white stay where they are.
+    fn relax(&self, points: &[(f32, f32)]) -> Vec<(f32, f32)> {
+        let grid = PointGrid::new(points, self.size(), self.spacing(points.len()));
+
+        // Each band sums the weight and weighted position of the samples
+        // nearest to each dot
+        let rows_per_band = self.height.div_ceil(BANDS);
+        let bands = parallel::map_range(BANDS, |band| {
+            let mut sums = vec![[0f64; 3]; points.len()];
+            let end = ((band + 1) * rows_per_band).min(self.height);
+            for row in band * rows_per_band..end {
+                let y = (row as f32 + 0.5) * self.sample_height;
+                for col in 0..self.width {
+                    // White samples don't pull on any dot
+                    let weight = self.values[row * self.width + col];
+                    if weight <= 0. {
+                        continue;
+                    }
+
+                    let x = (col as f32 + 0.5) * self.sample_width;
+                    let sum = &mut sums[grid.nearest((x, y))];
+                    let weight = f64::from(weight);
+                    sum[0] += weight;
+                    sum[1] += weight * f64::from(x);
+                    sum[2] += weight * f64::from(y);
+                }
+            }
+            sums
+        });
+
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, &point)| {
+                let [weight, x, y] = bands.iter().fold([0.; 3], |total, sums| {
+                    [
+                        total[0] + sums[i][0],
+                        total[1] + sums[i][1],
+                        total[2] + sums[i][2],
+                    ]
+                });
+
+                if weight > 0. {
+                    ((x / weight) as f32, (y / weight) as f32)
+                } else {
+                    point
+                }
+            })
+            .collect()
+    }
+
+    /// Average distance between `count` dots spread over the output.
+    fn spacing(&self, count: usize) -> f32 {
+        let (width, height) = self.size();
+        (width * height / count.max(1) as f32).sqrt()
+    }
+}
+
+/// Dots bucketed into square cells, for finding the nearest one quickly.
+struct PointGrid<'a> {
+    points: &'a [(f32, f32)],
+
+    /// Size of a cell in output units
+    cell: f32,
+
+    /// Number of cells across and down
+    cols: usize,
+    rows: usize,
+
+    /// Indices of the dots in each cell. The dots of cell `i` are
+    /// `indices[starts[i]..starts[i + 1]]`.
+    starts: Vec<usize>,
+    indices: Vec<usize>,
+}
+
+impl<'a> PointGrid<'a> {
+    fn new(points: &'a [(f32, f32)], (width, height): (f32, f32), cell: f32) -> Self {
+        let cols = ((width / cell).ceil() as usize).max(1);
+        let rows = ((height / cell).ceil() as usize).max(1);
+        let cell_of = |&(x, y): &(f32, f32)| {
+            let col = ((x / cell).max(0.) as usize).min(cols - 1);
+            let row = ((y / cell).max(0.) as usize).min(rows - 1);
+            row * cols + col
+        };
+
+        // Counting sort of the dots by cell
+        let mut starts = vec![0; cols * rows + 1];
+        for point in points {
+            starts[cell_of(point) + 1] += 1;
+        }
+        for i in 1..starts.len() {
+            starts[i] += starts[i - 1];
+        }
+        let mut next = starts.clone();
+        let mut indices = vec![0; points.len()];
+        for (i, point) in points.iter().enumerate() {
+            let cell = cell_of(point);
+            indices[next[cell]] = i;
+            next[cell] += 1;
+        }
+
+        PointGrid {
+            points,
+            cell,
+            cols,
+            rows,
+            starts,
+            indices,
+        }
+    }
+
+    /// Index of the dot nearest to `(x, y)`. There must be at least one dot.
+    fn nearest(&self, (x, y): (f32, f32)) -> usize {
+        let col = ((x / self.cell) as isize).clamp(0, self.cols as isize - 1);
+        let row = ((y / self.cell) as isize).clamp(0, self.rows as isize - 1);
+
+        let mut best = (f32::INFINITY, 0);
+
+        // Search rings of cells around the point's own cell. Dots beyond
+        // ring `ring` are at least `ring` cells away.
+        for ring in 0..self.cols.max(self.rows) as isize {
+            for r in row - ring..=row + ring {
+                if (r - row).abs() == ring {
+                    for c in col - ring..=col + ring {
+                        self.check_cell(c, r, (x, y), &mut best);
+                    }
+                } else {
+                    self.check_cell(col - ring, r, (x, y), &mut best);
+                    self.check_cell(col + ring, r, (x, y), &mut best);
+                }
+            }
+
+            if best.0 <= (ring as f32 * self.cell).powi(2) {
+                break;
+            }
+        }
+
+        best.1
+    }
+
+    /// Update `best` (squared distance and index) with the dots of one cell,
+    /// if that cell exists.
+    fn check_cell(&self, col: isize, row: isize, (x, y): (f32, f32), best: &mut (f32, usize)) {
+        if col < 0 || row < 0 || col >= self.cols as isize || row >= self.rows as isize {
+            return;
+        }
+
+        let cell = row as usize * self.cols + col as usize;
+        for &i in &self.indices[self.starts[cell]..self.starts[cell + 1]] {
+            let (px, py) = self.points[i];
+            let distance = (px - x).powi(2) + (py - y).powi(2);
+            if distance < best.0 {
+                *best = (distance, i);
+            }
+        }
+    }
+}
+
+/// Sort dots into rows `spacing` tall, going back and forth along them, so
+/// the moves between dots are short.
+fn travel_order(mut points: Vec<(f32, f32)>, spacing: f32) -> Vec<(f32, f32)> {
+    let key = |&(x, y): &(f32, f32)| {
+        let row = (y / spacing) as usize;
+        let along = if row % 2 == 1 { -x } else { x };
+        (row, along)
+    };
+    points.sort_by(|a, b| {
+        let (a, b) = (key(a), key(b));
+        a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+    });
+    points
+}
+
+/// Small xorshift random number generator. Stippling only needs a cheap,
+/// repeatable scatter, not good randomness.
+struct Rng(u64);
+
+impl Rng {
+    /// A random number from 0 to 1.
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    fn config() -> SinusoidShadingConfig {
+        SinusoidShadingConfig {
+            width: 40,
+            height: 20,
+            ..Default::default()
+        }
+    }
+
+    /// Stipple `luma` with `count` dots and 10 rounds of relaxation.
+    fn stipple(luma: &GrayImage, count: usize, cancel: &CancellationToken) -> Option<Vec<Vertex>> {
+        let density = Density::new(luma, &config(), count);
+        let tracker = Tracker::new(&|_, _| {}, cancel, 10);
+        density.stipple(count, 10, &tracker)
+    }
+
+    #[test]
+    fn dots_stay_on_the_dark_half() {
+        let luma = GrayImage::from_fn(40, 20, |x, _| Luma([if x < 20 { 0 } else { 255 }]));
+        let dots = stipple(&luma, 100, &CancellationToken::new()).unwrap();
+
+        assert_eq!(dots.len(), 100);
+        for dot in dots {
+            assert!(dot.x < 20. && (0. ..=20.).contains(&dot.y), "{:?}", dot);
+            assert_eq!(dot.intensity, Some(1.));
+        }
+    }
+
+    #[test]
+    fn white_image_has_no_dots() {
+        let luma = GrayImage::from_pixel(40, 20, Luma([255]));
+        assert_eq!(stipple(&luma, 100, &CancellationToken::new()), Some(vec![]));
+    }
+
+    #[test]
+    fn relaxation_spreads_dots_out() {
+        // On a uniform grey, relaxed dots are about evenly spaced, so none
+        // is much closer to its nearest neighbour than the average spacing
+        let luma = GrayImage::from_pixel(40, 20, Luma([128]));
+        let dots = stipple(&luma, 50, &CancellationToken::new()).unwrap();
+        let spacing = (40. * 20. / 50f32).sqrt();
+
+        for (i, a) in dots.iter().enumerate() {
+            let nearest = dots
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, b)| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt())
+                .fold(f32::INFINITY, f32::min);
+            assert!(nearest > spacing / 2., "{} < {}", nearest, spacing / 2.);
+        }
+    }
+
+    #[test]
+    fn cancelled() {
+        let luma = GrayImage::from_pixel(40, 20, Luma([0]));
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert_eq!(stipple(&luma, 100, &cancel), None);
+    }
+}
@@ -0,0 +1,614 @@
+use image::DynamicImage;
+
+use crate::geometry::{Layer, Subpath, Vertex};
+use crate::sinusoid::Tracker;
+use crate::stipple::Density;
+use crate::{CancellationToken, ImageProcessError, SinusoidShadingConfig};
+
+/// Number of nearest points considered as new neighbours by 2-opt
+const NEIGHBOURS: usize = 8;
+
+/// A straight line between two points
+type Edge = ((f32, f32), (f32, f32));
+
+/// Points improved between checks of the time budget and cancellation
+const CHECK_EVERY: usize = 256;
+
+/// Draw a grayscale image as a single path through stippled points.
+///
+/// # Returns
+/// * A single layer with one subpath, or `ImageProcessError::Cancelled`.
+pub(crate) fn trace_tour(
+    img: &DynamicImage,
+    config: &SinusoidShadingConfig,
+    progress: &(dyn Fn(usize, usize) + Sync),
+    cancel: &CancellationToken,
+) -> Result<Vec<Layer>, ImageProcessError> {
+    let density = Density::new(&img.to_luma8(), config, config.stipple_points);
+    let tracker = Tracker::new(progress, cancel, tour_line_count(config));
+
+    let tour = density
+        .stipple(config.stipple_points, config.stipple_iterations, &tracker)
+        .and_then(|points| tour(points, config, &tracker))
+        .ok_or(ImageProcessError::Cancelled)?;
+    Ok(vec![Layer::from_subpaths("tour", tour)])
+}
+
+/// Number of steps reported to a `Tracker` while stippling and touring one
+/// image.
+pub(crate) fn tour_line_count(config: &SinusoidShadingConfig) -> usize {
+    config.stipple_iterations + config.tsp_passes
+}
+
+/// Join points into one short open path.
+///
+/// The path starts as a nearest neighbour tour from the first point, which
+/// is then improved by 2-opt: wherever swapping two edges for two shorter
+/// ones (by reversing the part of the path between them) helps, it is done.
+/// Improvement stops after `config.tsp_passes` passes over the path, after
+/// `config.tsp_time` seconds, or when no swap helps.
+///
+/// Only swaps that join a point to one of its `NEIGHBOURS` nearest points are
+/// tried, which can leave crossings between edges whose ends aren't near
+/// neighbours. A final pass swaps every pair of crossing edges, so the path
+/// doesn't cross itself unless the budget runs out first. With no passes, the
+/// nearest neighbour tour is kept as it is.
+///
+/// # Arguments
+/// * `points` - The points to visit.
+/// * `config` - The configuration struct, for the budget.
+/// * `tracker` - Each pass is reported as a line.
+///
+/// # Returns
+/// * The path as a single subpath (or none, without any points), or `None`
+///   if it was cancelled.
+pub(crate) fn tour(
+    points: Vec<Vertex>,
+    config: &SinusoidShadingConfig,
+    tracker: &Tracker,
+) -> Option<Vec<Subpath>> {
+    let positions: Vec<_> = points.iter().map(Vertex::position).collect();
+    let mut tour = TwoOpt::new(&positions);
+    let deadline = Deadline::after(config.tsp_time);
+
+    let mut passes = 0;
+    while passes < config.tsp_passes {
+        let improved = tour.pass(|| tracker.cancelled() || deadline.passed());
+        if tracker.cancelled() {
+            return None;
+        }
+
+        passes += 1;
+        tracker.line_done();
+        if !improved || deadline.passed() {
+            break;
+        }
+    }
+
+    if config.tsp_passes > 0 {
+        tour.uncross(|| tracker.cancelled() || deadline.passed());
+        if tracker.cancelled() {
+            return None;
+        }
+    }
+
+    // Finish the progress of passes that weren't needed
+    for _ in passes..config.tsp_passes {
+        tracker.line_done();
+    }
+
+    let path = Subpath::polyline(tour.order.iter().map(|&i| points[i]));
+    Some(path.into_iter().collect())
+}
+
+/// An open path through points, improved by 2-opt.
+struct TwoOpt<'a> {
+    points: &'a [(f32, f32)],
+
+    /// Indices of the points in path order
+    order: Vec<usize>,
+
+    /// Position of each point in `order`
+    position: Vec<usize>,
+
+    /// The nearest points to each point
+    neighbours: Vec<Vec<usize>>,
+}
+
+impl<'a> TwoOpt<'a> {
+    /// Start with a nearest neighbour tour.
+    fn new(points: &'a [(f32, f32)]) -> Self {
+        let mut grid = Buckets::new(points);
+        let neighbours = (0..points.len())
+            .map(|i| grid.nearest(points[i], NEIGHBOURS, Some(i)))
+            .collect();
+
+        let mut order = Vec::with_capacity(points.len());
+        let mut next = (!points.is_empty()).then_some(0);
+        while let Some(i) = next {
+            grid.remove(i);
+            order.push(i);
+            next = grid.nearest(points[i], 1, None).first().copied();
+        }
+
+        let mut position = vec![0; points.len()];
+        for (p, &i) in order.iter().enumerate() {
+            position[i] = p;
+        }
+
+        TwoOpt {
+            points,
+            order,
+            position,
+            neighbours,
+        }
+    }
+
+    /// Try to improve the edges of every point once.
+    ///
+    /// # Arguments
+    /// * `stop` - Checked every `CHECK_EVERY` points. The pass ends early if
+    ///   it returns `true`.
+    ///
+    /// # Returns
+    /// * Whether the path got shorter.
+    fn pass(&mut self, stop: impl Fn() -> bool) -> bool {
+        let mut improved = false;
+        for p in 0..self.order.len() {
+            if p % CHECK_EVERY == 0 && stop() {
+                break;
+            }
+
+            let a = self.order[p];
+            for k in 0..self.neighbours[a].len() {
+                let q = self.position[self.neighbours[a][k]];
+
+                // Join `a` to the neighbour, either as the first edge of the
+                // reversed part or the last
+                let swapped = match q.cmp(&p) {
+                    std::cmp::Ordering::Greater => {
+                        self.try_swap(Some(p), q) || self.try_swap(p.checked_sub(1), q - 1)
+                    }
+                    std::cmp::Ordering::Less => {
+                        self.try_swap(Some(q), p) || self.try_swap(q.checked_sub(1), p - 1)
+                    }
+                    std::cmp::Ordering::Equal => false,
+                };
+
+                if swapped {
+                    improved = true;
+                    break;
+                }
+            }
+        }
+
+        improved
+    }
+
+    /// Reverse `order[start + 1..=end]` if that makes the path shorter.
+    ///
+    /// This swaps the edges before and after the reversed part, (`start`,
+    /// `start + 1`) and (`end`, `end + 1`), for (`start`, `end`) and
+    /// (`start + 1`, `end + 1`). Edges past either end of the path don't
+    /// exist, so reversing the start or end of the path only swaps one edge.
+    ///
+    /// # Returns
+    /// * Whether the part was reversed.
+    fn try_swap(&mut self, start: Option<usize>, end: usize) -> bool {
+        let first = start.map_or(0, |start| start + 1);
+        if first >= end {
+            return false;
+        }
+
+        let point = |p: usize| self.points[self.order[p]];
+        let length = |a: Option<usize>, b: Option<usize>| match (a, b) {
+            (Some(a), Some(b)) => distance(point(a), point(b)),
+            _ => 0.,
+        };
+        let after = (end + 1 < self.order.len()).then_some(end + 1);
+
+        let before_swap = length(start, Some(first)) + length(Some(end), after);
+        let after_swap = length(start, Some(end)) + length(Some(first), after);
+        if after_swap >= before_swap - 1e-4 {
+            return false;
+        }
+
+        self.reverse(first, end);
+        true
+    }
+
+    /// Swap every pair of crossing edges, until none are left.
+    ///
+    /// Reversing the part of the path between two crossing edges uncrosses
+    /// them and always makes the path shorter, but can cross other edges, so
+    /// crossings are searched for again until none are found.
+    ///
+    /// # Arguments
+    /// * `stop` - Checked before each search and every `CHECK_EVERY` swaps.
+    ///   Crossings may remain if it returns `true`.
+    ///
+    /// # Returns
+    /// * Whether every crossing was removed.
+    fn uncross(&mut self, stop: impl Fn() -> bool) -> bool {
+        loop {
+            if stop() {
+                return false;
+            }
+
+            let edges: Vec<_> = (0..self.order.len().saturating_sub(1))
+                .map(|p| self.edge(p))
+                .collect();
+            let crossings = crossing_segments(&edges);
+            if crossings.is_empty() {
+                return true;
+            }
+
+            for (n, (p, q)) in crossings.into_iter().enumerate() {
+                if n > 0 && n % CHECK_EVERY == 0 && stop() {
+                    return false;
+                }
+
+                // Earlier swaps may have moved these edges
+                if segments_cross(self.edge(p), self.edge(q)) {
+                    self.reverse(p + 1, q);
+                }
+            }
+        }
+    }
+
+    /// The edge from `order[p]` to the next point.
+    fn edge(&self, p: usize) -> Edge {
+        (self.points[self.order[p]], self.points[self.order[p + 1]])
+    }
+
+    /// Reverse `order[first..=end]`, keeping `position` up to date.
+    fn reverse(&mut self, first: usize, end: usize) {
+        self.order[first..=end].reverse();
+        for p in first..=end {
+            self.position[self.order[p]] = p;
+        }
+    }
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+/// Whether two segments cross at a single point inside both of them.
+///
+/// Segments that only touch, e.g. two edges sharing a point, or that lie
+/// along the same line don't cross.
+fn segments_cross((a, b): Edge, (c, d): Edge) -> bool {
+    // Which side of the line through `p` and `q` the point `r` is on. The
+    // products are exact in `f64`, so the sign can be trusted.
+    let side = |p: (f32, f32), q: (f32, f32), r: (f32, f32)| {
+        let (p, q, r) = (
+            (f64::from(p.0), f64::from(p.1)),
+            (f64::from(q.0), f64::from(q.1)),
+            (f64::from(r.0), f64::from(r.1)),
+        );
+        (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0)
+    };
+
+    side(a, b, c) * side(a, b, d) < 0. && side(c, d, a) * side(c, d, b) < 0.
+}
+
+/// Every pair of segments that cross.
+///
+/// Segments are added to each cell of a uniform grid that their bounding box
+/// covers, and only segments sharing a cell are tested against each other.
+///
+/// # Returns
+/// * Pairs of indices `(i, j)` with `i < j`, each once, in order.
+fn crossing_segments(segments: &[Edge]) -> Vec<(usize, usize)> {
+    if segments.is_empty() {
+        return Vec::new();
+    }
+
+    let mut min = (f32::INFINITY, f32::INFINITY);
+    let mut max = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+    let mut total = 0.;
+    for &(a, b) in segments {
+        for (x, y) in [a, b] {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        total += distance(a, b);
+    }
+
+    // Cells about as large as an average segment, but no more than a few
+    // cells per segment
+    let (width, height) = (max.0 - min.0, max.1 - min.1);
+    let cell = (total / segments.len() as f32)
+        .max((width * height / (4 * segments.len()) as f32).sqrt())
+        .max(1e-3);
+    let cols = (width / cell) as usize + 1;
+    let rows = (height / cell) as usize + 1;
+    let col_of = |x: f32| (((x - min.0) / cell) as usize).min(cols - 1);
+    let row_of = |y: f32| (((y - min.1) / cell) as usize).min(rows - 1);
+
+    let mut cells = vec![Vec::new(); cols * rows];
+    for (i, &(a, b)) in segments.iter().enumerate() {
+        for row in row_of(a.1.min(b.1))..=row_of(a.1.max(b.1)) {
+            for col in col_of(a.0.min(b.0))..=col_of(a.0.max(b.0)) {
+                cells[row * cols + col].push(i);
+            }
+        }
+    }
+
+    // Segments were added in order, so `i < j` within each cell
+    let mut pairs = Vec::new();
+    for cell in &cells {
+        for (k, &i) in cell.iter().enumerate() {
+            for &j in &cell[k + 1..] {
+                if segments_cross(segments[i], segments[j]) {
+                    pairs.push((i, j));
+                }
+            }
+        }
+    }
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+}
+
+/// Points bucketed into square cells, for finding near ones quickly.
+struct Buckets<'a> {
+    points: &'a [(f32, f32)],
+
+    /// Size of a cell
+    cell: f32,
+
+    /// Number of cells across and down
+    cols: usize,
+    rows: usize,
+
+    /// Indices of the points in each cell, row by row
+    cells: Vec<Vec<usize>>,
+}
+
+impl<'a> Buckets<'a> {
+    fn new(points: &'a [(f32, f32)]) -> Self {
+        let (width, height) = points
+            .iter()
+            .fold((0f32, 0f32), |(w, h), &(x, y)| (w.max(x), h.max(y)));
+
+        // About two points per cell
+        let cell = (width * height / points.len().max(1) as f32 * 2.)
+            .sqrt()
+            .max(1e-3);
+        let cols = (width / cell) as usize + 1;
+        let rows = (height / cell) as usize + 1;
+
+        let mut buckets = Buckets {
+            points,
+            cell,
+            cols,
+            rows,
+            cells: vec![Vec::new(); cols * rows],
+        };
+        for (i, &point) in points.iter().enumerate() {
+            let cell = buckets.cell_of(point);
+            buckets.cells[cell].push(i);
+        }
+        buckets
+    }
+
+    fn cell_of(&self, (x, y): (f32, f32)) -> usize {
+        let col = ((x / self.cell).max(0.) as usize).min(self.cols - 1);
+        let row = ((y / self.cell).max(0.) as usize).min(self.rows - 1);
+        row * self.cols + col
+    }
+
+    /// Take a point out, so it isn't found again.
+    fn remove(&mut self, i: usize) {
+        let cell = self.cell_of(self.points[i]);
+        self.cells[cell].retain(|&j| j != i);
+    }
+
+    /// The `count` nearest points to `point`, nearest first.
+    ///
+    /// # Arguments
+    /// * `point` - Where to search from.
+    /// * `count` - Number of points wanted.
+    /// * `skip` - A point to leave out, e.g. the one searched from.
+    fn nearest(&self, point: (f32, f32), count: usize, skip: Option<usize>) -> Vec<usize> {
+        let centre = self.cell_of(point);
+        let (col, row) = ((centre % self.cols) as isize, (centre / self.cols) as isize);
+
+        // Search rings of cells around the point's own cell, until the
+        // nearest `count` found are closer than anything in the next ring
+        let mut found: Vec<(f32, usize)> = Vec::new();
+        for ring in 0..self.cols.max(self.rows) as isize {
+            for r in row - ring..=row + ring {
+                let step = if (r - row).abs() == ring {
+                    1
+                } else {
+                    2 * ring.max(1)
+                };
+                for c in (col - ring..=col + ring).step_by(step as usize) {
+                    if c < 0 || r < 0 || c >= self.cols as isize || r >= self.rows as isize {
+                        continue;
+                    }
+
+                    let cell = &self.cells[r as usize * self.cols + c as usize];
+                    found.extend(
+                        cell.iter()
+                            .filter(|&&i| Some(i) != skip)
+                            .map(|&i| (distance(point, self.points[i]), i)),
+                    );
+                }
+            }
+
+            if found.len() >= count {
+                found.sort_by(|a, b| a.0.total_cmp(&b.0));
+                if found[count - 1].0 <= ring as f32 * self.cell {
+                    break;
+                }
+            }
+        }
+
+        found.sort_by(|a, b| a.0.total_cmp(&b.0));
+        found.into_iter().take(count).map(|(_, i)| i).collect()
+    }
+}
+
+// The time budget is measured with `Instant` natively. WebAssembly in the
+// browser has no `Instant`, so the JavaScript clock is used there.
+
+/// A point in time after which improvement stops.
+#[cfg(not(target_arch = "wasm32"))]
+struct Deadline(Option<std::time::Instant>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Deadline {
+    /// `seconds` from now, or never if it isn't positive.
+    fn after(seconds: f32) -> Self {
+        let now = std::time::Instant::now();
+        Deadline((seconds > 0.).then(|| now + std::time::Duration::from_secs_f32(seconds)))
+    }
+
+    fn passed(&self) -> bool {
+        self.0.is_some_and(|end| std::time::Instant::now() >= end)
+    }
+}
+
+/// A point in time after which improvement stops.
+#[cfg(target_arch = "wasm32")]
+struct Deadline(Option<f64>);
+
+#[cfg(target_arch = "wasm32")]
+impl Deadline {
+    /// `seconds` from now, or never if it isn't positive.
+    fn after(seconds: f32) -> Self {
+        let now = js_sys::Date::now();
+        Deadline((seconds > 0.).then(|| now + f64::from(seconds) * 1000.))
+    }
+
+    fn passed(&self) -> bool {
+        self.0.is_some_and(|end| js_sys::Date::now() >= end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scattered points, the same every time.
+    fn scatter(count: usize) -> Vec<(f32, f32)> {
+        let mut state = 12345u32;
+        let mut next = move || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 8) as f32 / (1 << 24) as f32 * 100.
+        };
+        (0..count).map(|_| (next(), next())).collect()
+    }
+
+    fn length(path: &[(f32, f32)]) -> f32 {
+        path.windows(2).map(|w| distance(w[0], w[1])).sum()
+    }
+
+    /// Tour `points` with a budget of `passes`, returning the path's points.
+    fn tour_points(points: &[(f32, f32)], passes: usize) -> Vec<(f32, f32)> {
+        let config = SinusoidShadingConfig {
+            tsp_passes: passes,
+            tsp_time: 0.,
+            ..Default::default()
+        };
+        let cancel = CancellationToken::new();
+        let tracker = Tracker::new(&|_, _| {}, &cancel, passes);
+        let vertices = points.iter().map(|&point| point.into()).collect();
+
+        let paths = tour(vertices, &config, &tracker).unwrap();
+        assert_eq!(paths.len(), 1);
+        paths[0].vertices().map(|v| v.position()).collect()
+    }
+
+    #[test]
+    fn visits_every_point_once() {
+        let points = scatter(500);
+        let mut path = tour_points(&points, 10);
+        assert_eq!(path.len(), points.len());
+
+        let mut sorted = points.clone();
+        for list in [&mut path, &mut sorted] {
+            list.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        }
+        assert_eq!(path, sorted);
+    }
+
+    #[test]
+    fn two_opt_shortens_nearest_neighbour_tour() {
+        let points = scatter(500);
+        let nearest_neighbour = length(&tour_points(&points, 0));
+        let improved = length(&tour_points(&points, 10));
+        assert!(improved < nearest_neighbour);
+    }
+
+    #[test]
+    fn two_opt_removes_crossing() {
+        // (0, 0) to (1, 1) crosses (1, 0) to (0, 1)
+        let points = [(0., 0.), (1., 1.), (1., 0.), (0., 1.)];
+        let mut tour = TwoOpt::new(&points);
+        tour.order = vec![0, 1, 2, 3];
+        tour.position = vec![0, 1, 2, 3];
+
+        assert!(tour.pass(|| false));
+        let path: Vec<_> = tour.order.iter().map(|&i| points[i]).collect();
+        assert!((length(&path) - 3.).abs() < 1e-5, "{:?}", path);
+        assert!(!tour.pass(|| false));
+    }
+
+    #[test]
+    fn tour_does_not_cross_itself() {
+        let points = scatter(2000);
+        let path = tour_points(&points, 10);
+        let edges: Vec<_> = path.windows(2).map(|w| (w[0], w[1])).collect();
+
+        for (i, &a) in edges.iter().enumerate() {
+            for (j, &b) in edges.iter().enumerate().skip(i + 1) {
+                assert!(!segments_cross(a, b), "edges {} and {} cross", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn crossing_segments_matches_brute_force() {
+        let points = scatter(400);
+        let segments: Vec<_> = points.chunks(2).map(|w| (w[0], w[1])).collect();
+
+        let mut expected = Vec::new();
+        for i in 0..segments.len() {
+            for j in i + 1..segments.len() {
+                if segments_cross(segments[i], segments[j]) {
+                    expected.push((i, j));
+                }
+            }
+        }
+        assert!(!expected.is_empty());
+        assert_eq!(crossing_segments(&segments), expected);
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let points = scatter(300);
+        let buckets = Buckets::new(&points);
+        for (i, &point) in points.iter().enumerate().step_by(7) {
+            let mut expected: Vec<usize> = (0..points.len()).filter(|&j| j != i).collect();
+            expected.sort_by(|&a, &b| {
+                distance(point, points[a]).total_cmp(&distance(point, points[b]))
+            });
+            expected.truncate(NEIGHBOURS);
+
+            assert_eq!(buckets.nearest(point, NEIGHBOURS, Some(i)), expected);
+        }
+    }
+
+    #[test]
+    fn no_points() {
+        let cancel = CancellationToken::new();
+        let tracker = Tracker::new(&|_, _| {}, &cancel, 1);
+        let config = SinusoidShadingConfig::default();
+        assert_eq!(tour(Vec::new(), &config, &tracker), Some(Vec::new()));
+    }
+}
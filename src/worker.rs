@@ -0,0 +1,605 @@
+// Shading in a Web Worker.
+//
+// Shading a large image takes long enough to freeze the page, so the web app
+// hands the work to a dedicated worker. The worker loads this same wasm
+// module: `main` calls `run_if_worker` first, which answers shading jobs
+// instead of launching the app when it finds itself inside a worker.
+//
+// Messages are plain JS objects with a `kind` field:
+//
+// * page -> worker: `image` (width, height and RGB pixels), `render` (a job
+//   id and the configuration as a preset), `cancel` (a job id) and, on
+//   cross-origin isolated pages, `cancel-flag` (see below)
+// * worker -> page: `ready` once it is listening, then `done` (id and SVG) or
+//   `error` (id and message) for every render that isn't cancelled
+//
+// The worker keeps its cache between jobs, so outdated jobs are cancelled
+// rather than the worker restarted. A worker can't read messages while a job
+// is running, though. A `render` is only started once the messages queued
+// behind it have been read, so a newer `render` or a `cancel` replaces a job
+// that hasn't started yet. A job that has started checks a flag between rows,
+// which holds the id of the job the page is waiting for and is shared through
+// a SharedArrayBuffer. Browsers only allow those on cross-origin isolated
+// pages; elsewhere a job that has started runs to the end, and its result is
+// ignored.
+
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use image::{DynamicImage, RgbImage};
+use js_sys::{Array, Atomics, Int32Array, Object, Reflect, SharedArrayBuffer, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{
+    console, Blob, BlobPropertyBag, DedicatedWorkerGlobalScope, Event, MessageEvent, Url, Worker,
+    WorkerOptions, WorkerType,
+};
+
+use crate::{CancellationToken, ImageProcessError, ShadingCache, SinusoidShadingConfig};
+
+/// Where `dx build` puts the wasm-bindgen output. These must match
+/// `base_path` in Dioxus.toml (see also dist/index.html).
+const GLUE_JS: &str = "/img2laser/assets/dioxus/img2laser.js";
+const GLUE_WASM: &str = "/img2laser/assets/dioxus/img2laser_bg.wasm";
+
+thread_local! {
+    /// The flag from the `cancel-flag` message, in the worker
+    static CANCEL_FLAG: RefCell<Option<Int32Array>> = const { RefCell::new(None) };
+}
+
+/// State of the shading worker.
+#[derive(Default)]
+struct WorkerState {
+    /// The worker keeps its own cache, so only the stages affected by a
+    /// settings change are recomputed.
+    cache: Option<ShadingCache>,
+
+    /// The `render` message of the next job, until it starts
+    pending: Option<JsValue>,
+}
+
+/// Answer shading jobs if this wasm module was loaded by a shading worker.
+///
+/// # Returns
+/// * `true` if running inside a worker, in which case the app must not be
+///   launched.
+pub fn run_if_worker() -> bool {
+    let Ok(scope) = js_sys::global().dyn_into::<DedicatedWorkerGlobalScope>() else {
+        return false;
+    };
+
+    let state = Rc::new(RefCell::new(WorkerState::default()));
+
+    // Runs the pending job, once the messages that arrived while the last
+    // job was running have been read
+    let run_state = state.clone();
+    let reply_scope = scope.clone();
+    let run = Closure::<dyn FnMut()>::new(move || {
+        let mut state = run_state.borrow_mut();
+        let Some(job) = state.pending.take() else {
+            return;
+        };
+        if let Some(reply) = run_job(&mut state.cache, &job) {
+            post(&reply_scope, &reply);
+        }
+    });
+
+    let timer_scope = scope.clone();
+    let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        if handle_message(&mut state.borrow_mut(), &event.data()) {
+            let run = run.as_ref().unchecked_ref();
+            if let Err(e) =
+                timer_scope.set_timeout_with_callback_and_timeout_and_arguments_0(run, 0)
+            {
+                console::error_1(&e);
+            }
+        }
+    });
+    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+
+    // The handler is needed for as long as the worker runs
+    onmessage.forget();
+
+    post(&scope, &message(&[("kind", "ready".into())]));
+    true
+}
+
+/// Handle one message from the page.
+///
+/// # Returns
+/// * `true` if a job is now pending that isn't scheduled to run yet.
+fn handle_message(state: &mut WorkerState, data: &JsValue) -> bool {
+    match field_string(data, "kind").as_deref() {
+        Some("image") => {
+            state.cache = decode_image(data).map(|img| ShadingCache::new(&img));
+            state.pending = None;
+            false
+        }
+        Some("cancel-flag") => {
+            let flag = Reflect::get(data, &"flag".into())
+                .ok()
+                .and_then(|flag| flag.dyn_into::<Int32Array>().ok());
+            CANCEL_FLAG.with(|f| *f.borrow_mut() = flag);
+            false
+        }
+        Some("cancel") => {
+            let id = Reflect::get(data, &"id".into()).ok();
+            let pending_id = state
+                .pending
+                .as_ref()
+                .and_then(|job| Reflect::get(job, &"id".into()).ok());
+            if id.is_some() && id == pending_id {
+                state.pending = None;
+            }
+            false
+        }
+        Some("render") => {
+            // A timer is already set if there was a pending job
+            state.pending.replace(data.clone()).is_none()
+        }
+        _ => false,
+    }
+}
+
+/// Run a `render` job.
+///
+/// # Returns
+/// * The reply to send back, unless the job was cancelled.
+fn run_job(cache: &mut Option<ShadingCache>, data: &JsValue) -> Option<JsValue> {
+    let id = Reflect::get(data, &"id".into()).ok()?;
+    let job_id = id.as_f64()? as u32;
+
+    let reply = match render_job(cache, data, job_id) {
+        Ok(svg) => message(&[("kind", "done".into()), ("id", id), ("svg", svg.into())]),
+        Err(RenderError::Cancelled) => return None,
+        Err(RenderError::Failed(e)) => {
+            message(&[("kind", "error".into()), ("id", id), ("message", e.into())])
+        }
+    };
+    Some(reply)
+}
+
+/// Why a job has no result.
+enum RenderError {
+    /// The page has moved on to another job
+    Cancelled,
+
+    /// The job failed, with a message for the page
+    Failed(String),
+}
+
+impl From<&str> for RenderError {
+    fn from(message: &str) -> Self {
+        RenderError::Failed(message.to_string())
+    }
+}
+
+/// Shade the current image with the configuration from a `render` message.
+fn render_job(
+    cache: &mut Option<ShadingCache>,
+    data: &JsValue,
+    id: u32,
+) -> Result<String, RenderError> {
+    let cache = cache.as_mut().ok_or("no image to shade")?;
+    let preset = field_string(data, "preset").ok_or("missing configuration")?;
+
+    let mut config = SinusoidShadingConfig::default();
+    config
+        .apply_preset(&preset)
+        .map_err(|e| RenderError::Failed(e.to_string()))?;
+
+    let cancel = CancellationToken::new();
+    let progress = |_, _| {
+        if job_outdated(id) {
+            cancel.cancel();
+        }
+    };
+
+    match cache.render_with_progress(&config, progress, &cancel) {
+        Ok(svg) => Ok(svg.to_string()),
+        Err(ImageProcessError::Cancelled) => Err(RenderError::Cancelled),
+        Err(e) => Err(RenderError::Failed(e.to_string())),
+    }
+}
+
+/// Whether the page is waiting for a job other than `id`, going by the
+/// shared flag. Without the flag, a job runs to the end.
+fn job_outdated(id: u32) -> bool {
+    CANCEL_FLAG.with(|flag| {
+        flag.borrow()
+            .as_ref()
+            .and_then(|flag| Atomics::load(flag, 0).ok())
+            .is_some_and(|latest| latest != id as i32)
+    })
+}
+
+/// Runs the shading pipeline in a Web Worker on behalf of the page.
+///
+/// Results arrive asynchronously. Until then `svg` returns the previous
+/// result and `busy` is true. If the worker can't be started, images are
+/// shaded on the page instead, like before workers were used.
+pub struct BackgroundShader {
+    /// State shared with the worker's event handlers
+    shared: Rc<RefCell<Shared>>,
+
+    /// The current worker
+    handle: Option<WorkerHandle>,
+
+    /// Object URL of the worker's bootstrap script, created on first use
+    script_url: Option<String>,
+
+    /// Used instead of the worker if it fails
+    fallback: ShadingCache,
+
+    /// Configuration of the most recent request
+    requested: Option<SinusoidShadingConfig>,
+}
+
+/// State that the worker's event handlers need as well.
+struct Shared {
+    /// The current worker, once it has been started
+    worker: Option<Worker>,
+
+    /// Whether the worker is listening for messages yet
+    ready: bool,
+
+    /// Whether the worker could not be started
+    failed: bool,
+
+    /// Whether a job is waiting for its result
+    busy: bool,
+
+    /// The most recent result
+    svg: String,
+
+    /// The `image` message for the current image
+    image: JsValue,
+
+    /// Id of the job the page is waiting for, or -1, shared with the worker
+    /// if the page is cross-origin isolated
+    cancel_flag: Option<Int32Array>,
+
+    /// Id and `render` message of the most recent job, until it is done
+    job: Option<(u32, JsValue)>,
+
+    /// Id for the next job
+    next_id: u32,
+
+    /// Called when a result arrives, to re-render whatever shows it
+    listener: Option<Arc<dyn Fn()>>,
+}
+
+/// A worker and its event handlers, which must live as long as it does.
+struct WorkerHandle {
+    worker: Worker,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+    _onerror: Closure<dyn FnMut(Event)>,
+}
+
+impl Drop for WorkerHandle {
+    fn drop(&mut self) {
+        // Events that are already queued must not reach the dropped handlers
+        self.worker.set_onmessage(None);
+        self.worker.set_onerror(None);
+        self.worker.terminate();
+    }
+}
+
+impl BackgroundShader {
+    /// Start a worker for an image.
+    pub fn new(img: &DynamicImage) -> Self {
+        let shared = Shared {
+            worker: None,
+            ready: false,
+            failed: false,
+            busy: false,
+            svg: String::new(),
+            image: image_message(img),
+            cancel_flag: cancel_flag(),
+            job: None,
+            next_id: 0,
+            listener: None,
+        };
+
+        let mut shader = BackgroundShader {
+            shared: Rc::new(RefCell::new(shared)),
+            handle: None,
+            script_url: None,
+            fallback: ShadingCache::new(img),
+            requested: None,
+        };
+        shader.spawn();
+        shader
+    }
+
+    /// Set the function to call when a result arrives.
+    pub fn set_listener(&mut self, listener: Arc<dyn Fn()>) {
+        self.shared.borrow_mut().listener = Some(listener);
+    }
+
+    /// Replace the image. A job for the old image is cancelled.
+    pub fn set_image(&mut self, img: &DynamicImage) {
+        self.fallback.set_image(img);
+        self.requested = None;
+
+        let mut shared = self.shared.borrow_mut();
+        shared.image = image_message(img);
+        shared.cancel_job();
+        shared.busy = false;
+        shared.post(&shared.image);
+    }
+
+    /// Ask for the image to be shaded with `config`.
+    ///
+    /// Does nothing if this is the configuration of the previous request. A
+    /// job that is still running for another configuration is cancelled.
+    pub fn request(&mut self, config: &SinusoidShadingConfig) {
+        if self.shared.borrow().failed {
+            self.render_here(config);
+            return;
+        }
+        if self.requested.as_ref() == Some(config) {
+            return;
+        }
+        self.requested = Some(config.clone());
+
+        let mut shared = self.shared.borrow_mut();
+        shared.cancel_job();
+
+        let id = shared.next_id;
+        shared.next_id += 1;
+        shared.set_waiting_for(id as i32);
+
+        let job = message(&[
+            ("kind", "render".into()),
+            ("id", id.into()),
+            ("preset", config.to_preset().into()),
+        ]);
+        shared.post(&job);
+        shared.job = Some((id, job));
+        shared.busy = true;
+    }
+
+    /// Whether a result is still on its way.
+    pub fn busy(&self) -> bool {
+        self.shared.borrow().busy
+    }
+
+    /// The most recent SVG markup. Empty until the first result arrives.
+    pub fn svg(&self) -> Ref<'_, str> {
+        Ref::map(self.shared.borrow(), |s| s.svg.as_str())
+    }
+
+    /// Shade on the page, for when the worker failed.
+    fn render_here(&mut self, config: &SinusoidShadingConfig) {
+        let mut shared = self.shared.borrow_mut();
+        if shared.busy || self.requested.as_ref() != Some(config) {
+            shared.svg = self.fallback.render(config).to_string();
+            shared.busy = false;
+            self.requested = Some(config.clone());
+        }
+    }
+
+    /// Start the worker.
+    fn spawn(&mut self) {
+        match self.start_worker() {
+            Ok(handle) => {
+                self.shared.borrow_mut().worker = Some(handle.worker.clone());
+                self.handle = Some(handle);
+            }
+            Err(e) => {
+                console::error_2(&"could not start the shading worker:".into(), &e);
+                self.shared.borrow_mut().failed = true;
+            }
+        }
+    }
+
+    fn start_worker(&mut self) -> Result<WorkerHandle, JsValue> {
+        let url = match &self.script_url {
+            Some(url) => url.clone(),
+            None => {
+                let url = bootstrap_url()?;
+                self.script_url = Some(url.clone());
+                url
+            }
+        };
+
+        let mut options = WorkerOptions::new();
+        options.type_(WorkerType::Module);
+        let worker = Worker::new_with_options(&url, &options)?;
+
+        let shared = self.shared.clone();
+        let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            on_worker_message(&shared, &event.data());
+        });
+        worker.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+
+        let shared = self.shared.clone();
+        let onerror = Closure::<dyn FnMut(Event)>::new(move |_event: Event| {
+            on_worker_error(&shared);
+        });
+        worker.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+
+        Ok(WorkerHandle {
+            worker,
+            _onmessage: onmessage,
+            _onerror: onerror,
+        })
+    }
+}
+
+impl Shared {
+    /// Send a message to the worker, if it is listening. Messages sent before
+    /// then would be lost, so `on_worker_message` catches up once it is ready.
+    fn post(&self, message: &JsValue) {
+        if let (true, Some(worker)) = (self.ready, &self.worker) {
+            if let Err(e) = worker.post_message(message) {
+                console::error_1(&e);
+            }
+        }
+    }
+
+    /// Cancel the job the page is waiting for, if any. The worker drops it
+    /// if it hasn't started, or stops it between rows through the flag.
+    fn cancel_job(&mut self) {
+        if let Some((id, _)) = self.job.take() {
+            self.set_waiting_for(-1);
+            self.post(&message(&[("kind", "cancel".into()), ("id", id.into())]));
+        }
+    }
+
+    /// Tell a running job which job the page is waiting for, if the flag is
+    /// shared with the worker.
+    fn set_waiting_for(&self, id: i32) {
+        if let Some(flag) = &self.cancel_flag {
+            let _ = Atomics::store(flag, 0, id);
+        }
+    }
+}
+
+/// Handle a message from the worker, on the page.
+fn on_worker_message(shared: &RefCell<Shared>, data: &JsValue) {
+    let mut s = shared.borrow_mut();
+
+    match field_string(data, "kind").as_deref() {
+        Some("ready") => {
+            s.ready = true;
+            if let Some(flag) = &s.cancel_flag {
+                s.post(&message(&[
+                    ("kind", "cancel-flag".into()),
+                    ("flag", flag.clone().into()),
+                ]));
+            }
+            s.post(&s.image);
+            if let Some((_, job)) = &s.job {
+                s.post(job);
+            }
+            return;
+        }
+        Some(kind @ ("done" | "error")) => {
+            // Results of cancelled jobs are ignored
+            let id = Reflect::get(data, &"id".into())
+                .ok()
+                .and_then(|id| id.as_f64());
+            if !matches!(&s.job, Some((job_id, _)) if Some(f64::from(*job_id)) == id) {
+                return;
+            }
+
+            if kind == "done" {
+                s.svg = field_string(data, "svg").unwrap_or_default();
+            } else {
+                let error = field_string(data, "message").unwrap_or_default();
+                console::error_1(&format!("shading failed: {}", error).into());
+            }
+            s.job = None;
+            s.busy = false;
+        }
+        _ => return,
+    }
+
+    // The listener may render straight away, which needs the state
+    let listener = s.listener.clone();
+    drop(s);
+    if let Some(listener) = listener {
+        listener();
+    }
+}
+
+/// The worker failed to load or crashed. Shade on the page from now on.
+fn on_worker_error(shared: &RefCell<Shared>) {
+    let mut s = shared.borrow_mut();
+    console::error_1(&"the shading worker failed, shading on the page instead".into());
+    s.failed = true;
+    s.worker = None;
+
+    let listener = s.listener.clone();
+    drop(s);
+    if let Some(listener) = listener {
+        listener();
+    }
+}
+
+/// A flag for `Shared::cancel_flag`, which needs a SharedArrayBuffer, if the
+/// page is cross-origin isolated.
+fn cancel_flag() -> Option<Int32Array> {
+    let isolated = Reflect::get(&js_sys::global(), &"crossOriginIsolated".into()).ok()?;
+    if !isolated.as_bool()? {
+        return None;
+    }
+
+    let flag = Int32Array::new(&SharedArrayBuffer::new(4));
+    flag.fill(-1, 0, 1);
+    Some(flag)
+}
+
+/// Create an object URL for a module script that loads this wasm module.
+///
+/// The worker needs a script of its own, and this saves shipping one next to
+/// the files `dx build` generates. The page's origin is needed because
+/// relative imports don't work from an object URL.
+fn bootstrap_url() -> Result<String, JsValue> {
+    let origin = web_sys::window().ok_or("no window")?.location().origin()?;
+
+    // Same as dist/index.html. `main` finds itself in a worker and calls
+    // `run_if_worker`.
+    let script = format!(
+        r#"
+        import init from "{origin}{GLUE_JS}";
+        const wasm = await init("{origin}{GLUE_WASM}");
+        if (wasm.__wbindgen_start == undefined) {{
+            wasm.main();
+        }}
+        "#
+    );
+
+    let mut options = BlobPropertyBag::new();
+    options.type_("text/javascript");
+    let blob = Blob::new_with_str_sequence_and_options(&Array::of1(&script.into()), &options)?;
+    Url::create_object_url_with_blob(&blob)
+}
+
+/// The `image` message for an image. Shading only needs the RGB pixels
+/// (colour for separations, grayscale otherwise).
+fn image_message(img: &DynamicImage) -> JsValue {
+    let rgb = img.to_rgb8();
+    message(&[
+        ("kind", "image".into()),
+        ("width", rgb.width().into()),
+        ("height", rgb.height().into()),
+        ("pixels", Uint8Array::from(rgb.as_raw().as_slice()).into()),
+    ])
+}
+
+/// Read the image from an `image` message.
+fn decode_image(data: &JsValue) -> Option<DynamicImage> {
+    let field = |key: &str| Reflect::get(data, &key.into()).ok();
+
+    let width = field("width")?.as_f64()? as u32;
+    let height = field("height")?.as_f64()? as u32;
+    let pixels = field("pixels")?.dyn_into::<Uint8Array>().ok()?.to_vec();
+
+    RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+}
+
+/// Build a message object from its fields.
+fn message(fields: &[(&str, JsValue)]) -> JsValue {
+    let object = Object::new();
+    for (key, value) in fields {
+        // Setting a property on a plain object can't fail
+        let _ = Reflect::set(&object, &(*key).into(), value);
+    }
+    object.into()
+}
+
+/// Get a string field of a message.
+fn field_string(data: &JsValue, key: &str) -> Option<String> {
+    Reflect::get(data, &key.into()).ok()?.as_string()
+}
+
+/// Send a message from the worker to the page.
+fn post(scope: &DedicatedWorkerGlobalScope, message: &JsValue) {
+    if let Err(e) = scope.post_message(message) {
+        console::error_1(&e);
+    }
+}
@@ -0,0 +1,46 @@
+use image::{DynamicImage, GrayImage, Luma};
+
+use img2laser::{Canvas, ShadingMethod, Sinusoid, SinusoidShadingConfig};
+
+/// A gradient from black to light grey with a checkerboard over it, so rows
+/// cross both smooth and sudden changes.
+fn test_image() -> DynamicImage {
+    DynamicImage::ImageLuma8(GrayImage::from_fn(96, 64, |x, y| {
+        let checker = if (x / 12 + y / 12) % 2 == 1 { 80 } else { 0 };
+        Luma([(x * 160 / 95 + checker) as u8])
+    }))
+}
+
+/// The sinusoids are traced row by row without the intermediate arrays of
+/// the original implementation. `data/sinusoid.svg` was written by that
+/// implementation, and the output must not change.
+#[test]
+fn matches_golden_output() {
+    let config = SinusoidShadingConfig {
+        width: 64,
+        height: 48,
+        lines: 6,
+        ..Default::default()
+    };
+
+    let svg = img2laser::process_image(&test_image(), &config).to_string();
+    assert_eq!(svg, include_str!("data/sinusoid.svg"));
+}
+
+/// The sinusoid method can be used on its own, as a downstream crate would,
+/// and draws the same as `shade` with `Method::Sinusoid`.
+#[test]
+fn sinusoid_method_matches_shade() {
+    let config = SinusoidShadingConfig {
+        width: 64,
+        height: 48,
+        lines: 6,
+        ..Default::default()
+    };
+    let canvas = Canvas::from(&config);
+
+    let geometry = Sinusoid::new(&config).shade(&test_image(), &canvas);
+    assert_eq!(geometry, img2laser::shade(&test_image(), &config));
+    assert_eq!(geometry.layers.len(), 1);
+    assert_eq!(geometry.layers[0].subpaths.len(), 6);
+}